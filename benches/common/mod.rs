@@ -0,0 +1,154 @@
+// Helpers shared between the benchmarks.
+
+#![allow(dead_code)]
+
+use noise::sodium_wrapper::SodiumResolver;
+use noise::wrapper::NoiseWrapper;
+use snow::params::DHChoice;
+use snow::{CryptoResolver, DefaultResolver, NoiseBuilder, Session};
+
+pub static MESSAGE_SIZES: [usize; 4] = [1_024, 16_384, 65_536, 1_024 * 1_024];
+pub static CIPHERS: [&str; 2] = ["ChaChaPoly", "AESGCM"];
+
+/// Crypto backend the benchmark is run against.
+#[derive(Clone, Copy)]
+pub struct Backend {
+    pub name: &'static str,
+    pub resolver: fn() -> Box<CryptoResolver>,
+}
+
+pub fn backends() -> Vec<Backend> {
+    let mut backends = vec![
+        Backend {
+            name: "default",
+            resolver: || Box::new(DefaultResolver {}),
+        },
+        Backend {
+            name: "sodium",
+            resolver: || Box::new(SodiumResolver::new()),
+        },
+    ];
+
+    #[cfg(feature = "ring-resolver")]
+    backends.push(Backend {
+        name: "ring",
+        resolver: || Box::new(::snow::RingResolver {}),
+    });
+
+    backends
+}
+
+/// Benchmark label containing backend, cipher and whether SIMD optimizations are enabled.
+pub fn label(backend: &Backend, cipher: &str) -> String {
+    let simd = if cfg!(feature = "nightly") { "simd" } else { "no-simd" };
+    format!("{}/{}/{}", backend.name, cipher, simd)
+}
+
+pub fn params(pattern: &str, cipher: &str) -> String {
+    format!("Noise_{}_25519_{}_BLAKE2s", pattern, cipher)
+}
+
+/// Generates static keypair as `(public_key, private_key)`.
+pub fn generate_keypair(backend: &Backend) -> (Vec<u8>, Vec<u8>) {
+    let resolver = (backend.resolver)();
+    let mut rng = resolver.resolve_rng().unwrap();
+    let mut dh = resolver.resolve_dh(&DHChoice::Curve25519).unwrap();
+    dh.generate(&mut *rng);
+    (dh.pubkey().to_vec(), dh.privkey().to_vec())
+}
+
+/// Handshake pattern under benchmark.
+#[derive(Clone, Copy)]
+pub struct Pattern {
+    pub name: &'static str,
+    pub initiator_static: bool,
+    pub responder_static_known: bool,
+    pub messages: usize,
+}
+
+pub static PATTERNS: [Pattern; 3] = [
+    Pattern {
+        name: "XX",
+        initiator_static: true,
+        responder_static_known: false,
+        messages: 3,
+    },
+    Pattern {
+        name: "IK",
+        initiator_static: true,
+        responder_static_known: true,
+        messages: 2,
+    },
+    Pattern {
+        name: "NK",
+        initiator_static: false,
+        responder_static_known: true,
+        messages: 2,
+    },
+];
+
+/// Static keys of both peers, generated once per benchmark.
+pub struct Keys {
+    pub initiator: (Vec<u8>, Vec<u8>),
+    pub responder: (Vec<u8>, Vec<u8>),
+}
+
+impl Keys {
+    pub fn generate(backend: &Backend) -> Self {
+        Keys {
+            initiator: generate_keypair(backend),
+            responder: generate_keypair(backend),
+        }
+    }
+}
+
+/// Performs a full handshake and returns both sessions in transport mode.
+pub fn handshake(
+    backend: &Backend,
+    pattern: &Pattern,
+    cipher: &str,
+    keys: &Keys,
+) -> (Session, Session) {
+    let noise_params = params(pattern.name, cipher);
+
+    let mut builder = NoiseBuilder::with_resolver(noise_params.parse().unwrap(), (backend.resolver)());
+    if pattern.initiator_static {
+        builder = builder.local_private_key(&keys.initiator.1);
+    }
+    if pattern.responder_static_known {
+        builder = builder.remote_public_key(&keys.responder.0);
+    }
+    let mut initiator = builder.build_initiator().unwrap();
+
+    let mut responder = NoiseBuilder::with_resolver(noise_params.parse().unwrap(), (backend.resolver)())
+        .local_private_key(&keys.responder.1)
+        .build_responder()
+        .unwrap();
+
+    let mut msg = [0u8; 1024];
+    let mut payload = [0u8; 1024];
+    for i in 0..pattern.messages {
+        let (writer, reader) = if i % 2 == 0 {
+            (&mut initiator, &mut responder)
+        } else {
+            (&mut responder, &mut initiator)
+        };
+        let len = writer.write_message(&[], &mut msg).unwrap();
+        reader.read_message(&msg[..len], &mut payload).unwrap();
+    }
+
+    (
+        initiator.into_transport_mode().unwrap(),
+        responder.into_transport_mode().unwrap(),
+    )
+}
+
+/// Creates connected pair of `NoiseWrapper`s in transport mode using XX pattern.
+pub fn transport_pair(backend: &Backend, cipher: &str) -> (NoiseWrapper, NoiseWrapper) {
+    let keys = Keys::generate(backend);
+    let (initiator, responder) = handshake(backend, &PATTERNS[0], cipher, &keys);
+    (
+        NoiseWrapper::from_session(initiator),
+        NoiseWrapper::from_session(responder),
+    )
+}
@@ -0,0 +1,73 @@
+#[macro_use]
+extern crate criterion;
+extern crate bytes;
+extern crate noise;
+extern crate snow;
+
+mod common;
+
+use bytes::BytesMut;
+use common::{backends, label, transport_pair, CIPHERS, MESSAGE_SIZES};
+use criterion::{Criterion, ParameterizedBenchmark, Throughput};
+
+const LARGE_MESSAGE_SIZE: usize = 10 * 1_024 * 1_024;
+
+fn message_sizes() -> Vec<usize> {
+    let mut sizes = MESSAGE_SIZES.to_vec();
+    sizes.push(LARGE_MESSAGE_SIZE);
+    sizes
+}
+
+fn bench_encrypt(c: &mut Criterion) {
+    for backend in backends() {
+        for cipher in CIPHERS.iter() {
+            c.bench(
+                &format!("encrypt_msg/{}", label(&backend, cipher)),
+                ParameterizedBenchmark::new(
+                    "encrypt_msg",
+                    move |b, &size| {
+                        let (mut initiator, _) = transport_pair(&backend, cipher);
+                        let msg = vec![0u8; size];
+                        let mut buf = BytesMut::with_capacity(size * 2);
+                        b.iter(|| {
+                            buf.clear();
+                            initiator.encrypt_msg(&msg, &mut buf).unwrap();
+                        })
+                    },
+                    message_sizes(),
+                ).throughput(|&size| Throughput::Bytes(size as u32)),
+            );
+        }
+    }
+}
+
+fn bench_decrypt(c: &mut Criterion) {
+    for backend in backends() {
+        for cipher in CIPHERS.iter() {
+            c.bench(
+                &format!("decrypt_msg/{}", label(&backend, cipher)),
+                ParameterizedBenchmark::new(
+                    "decrypt_msg",
+                    move |b, &size| {
+                        let (mut initiator, mut responder) = transport_pair(&backend, cipher);
+                        let msg = vec![0u8; size];
+                        let mut encrypted = BytesMut::new();
+                        // Nonces must match, so every decryption gets its own encrypted message.
+                        b.iter_with_setup(
+                            || {
+                                encrypted.clear();
+                                initiator.encrypt_msg(&msg, &mut encrypted).unwrap();
+                                encrypted.clone()
+                            },
+                            |mut buf| responder.decrypt_msg(&mut buf).unwrap().unwrap(),
+                        )
+                    },
+                    message_sizes(),
+                ).throughput(|&size| Throughput::Bytes(size as u32)),
+            );
+        }
+    }
+}
+
+criterion_group!(benches, bench_encrypt, bench_decrypt);
+criterion_main!(benches);
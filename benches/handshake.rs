@@ -0,0 +1,28 @@
+#[macro_use]
+extern crate criterion;
+extern crate noise;
+extern crate snow;
+
+mod common;
+
+use common::{backends, handshake, label, Keys, CIPHERS, PATTERNS};
+use criterion::{Benchmark, Criterion, Throughput};
+
+fn bench_handshake(c: &mut Criterion) {
+    for backend in backends() {
+        for cipher in CIPHERS.iter() {
+            for pattern in PATTERNS.iter() {
+                let keys = Keys::generate(&backend);
+                c.bench(
+                    &format!("handshake/{}", label(&backend, cipher)),
+                    Benchmark::new(pattern.name, move |b| {
+                        b.iter(|| handshake(&backend, pattern, cipher, &keys))
+                    }).throughput(Throughput::Elements(1)),
+                );
+            }
+        }
+    }
+}
+
+criterion_group!(benches, bench_handshake);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+#[macro_use]
+extern crate criterion;
+extern crate bytes;
+extern crate futures;
+extern crate noise;
+extern crate snow;
+extern crate tokio_core;
+extern crate tokio_io;
+
+mod common;
+
+use bytes::BytesMut;
+use common::{backends, label, transport_pair, CIPHERS, MESSAGE_SIZES};
+use criterion::{Criterion, ParameterizedBenchmark, Throughput};
+use futures::{Future, Sink, Stream};
+use noise::noise_codec::MessagesCodec;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_io::AsyncRead;
+
+fn bench_framed_loopback(c: &mut Criterion) {
+    for backend in backends() {
+        for cipher in CIPHERS.iter() {
+            c.bench(
+                &format!("framed_loopback/{}", label(&backend, cipher)),
+                ParameterizedBenchmark::new(
+                    "framed_loopback",
+                    move |b, &size| {
+                        let mut core = Core::new().unwrap();
+                        let handle = core.handle();
+
+                        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+                        let addr = listener.local_addr().unwrap();
+
+                        let server = listener
+                            .incoming()
+                            .into_future()
+                            .map(|(stream, _)| stream.unwrap().0)
+                            .map_err(|(e, _)| e);
+                        let client = TcpStream::connect(&addr, &handle);
+                        let (server, client) = core.run(server.join(client)).unwrap();
+
+                        // Handshake isn't measured here, so sessions of the chosen backend are
+                        // established in memory and only the transport runs over the socket.
+                        let (initiator, responder) = transport_pair(&backend, cipher);
+                        let (client_sink, _) = client.framed(MessagesCodec::new(initiator)).split();
+                        let (_, server_stream) = server.framed(MessagesCodec::new(responder)).split();
+                        let mut connection = Some((client_sink, server_stream));
+                        let msg = BytesMut::from(vec![0u8; size]);
+
+                        b.iter(|| {
+                            let (sink, stream) = connection.take().unwrap();
+                            let send = sink.send(msg.clone());
+                            let receive = stream.into_future().map_err(|(e, _)| e);
+                            let (sink, (received, stream)) = core.run(send.join(receive)).unwrap();
+                            assert_eq!(received.map(|msg| msg.len()), Some(size));
+                            connection = Some((sink, stream));
+                        })
+                    },
+                    MESSAGE_SIZES.to_vec(),
+                ).throughput(|&size| Throughput::Bytes(size as u32)),
+            );
+        }
+    }
+}
+
+criterion_group!(benches, bench_framed_loopback);
+criterion_main!(benches);
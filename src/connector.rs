@@ -0,0 +1,354 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Outgoing connection which survives transport failures.
+
+use bytes::BytesMut;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use noise_codec::MessagesCodec;
+use noise_main::{ConnectionResult, NoiseHandshake};
+use rand::{thread_rng, Rng};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::codec::Framed;
+use wrapper::HandshakeParams;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Greatest power of two the initial backoff is multiplied by.
+const MAX_BACKOFF_EXPONENT: u32 = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectorConfig {
+    /// Delay before the first reconnect, it is doubled after each failed attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Maximal number of outbound messages buffered while the connection is down.
+    pub buffer_size: usize,
+}
+
+impl Default for ConnectorConfig {
+    fn default() -> Self {
+        ConnectorConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            buffer_size: 1024,
+        }
+    }
+}
+
+impl ConnectorConfig {
+    /// Returns delay before the reconnect after `failures` failed attempts in a row.
+    ///
+    /// Delay grows exponentially and is randomized between its half and full value,
+    /// so that peers which have lost connection at once don't reconnect at once.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let exponent = cmp::min(failures.saturating_sub(1), MAX_BACKOFF_EXPONENT);
+        let delay = self.initial_backoff
+            .checked_mul(1 << exponent)
+            .map_or(self.max_backoff, |delay| cmp::min(delay, self.max_backoff));
+
+        let millis = delay.as_secs() * 1000 + u64::from(delay.subsec_nanos() / 1_000_000);
+        Duration::from_millis(millis / 2 + thread_rng().gen_range(0, millis / 2 + 1))
+    }
+}
+
+enum State {
+    Connecting(ConnectionResult),
+    Connected(Framed<TcpStream, MessagesCodec>),
+    Backoff(Timeout),
+}
+
+/// Connection to the given address, which is reestablished whenever the transport fails.
+///
+/// Messages sent while the connection is down are buffered, messages which have
+/// already been written to the failed connection may be lost. If `remote_key`
+/// of the params is set, the peer has to present this static key, otherwise
+/// the connector fails with `io::ErrorKind::InvalidData` error.
+///
+/// `Stream` and `Sink` share the connection, so both halves should be polled
+/// by the same task.
+pub struct NoiseConnector {
+    addr: SocketAddr,
+    params: HandshakeParams,
+    config: ConnectorConfig,
+    handle: Handle,
+    state: State,
+    // Failed attempts since the last established connection.
+    failures: u32,
+    outbound: VecDeque<BytesMut>,
+}
+
+impl NoiseConnector {
+    /// Creates connector and starts connecting.
+    pub fn new(addr: SocketAddr, params: HandshakeParams, config: ConnectorConfig, handle: &Handle) -> Self {
+        let state = State::Connecting(connect(&addr, &params, handle));
+        NoiseConnector {
+            addr,
+            params,
+            config,
+            handle: handle.clone(),
+            state,
+            failures: 0,
+            outbound: VecDeque::new(),
+        }
+    }
+
+    /// Returns `true` if the connection is currently established.
+    pub fn is_connected(&self) -> bool {
+        match self.state {
+            State::Connected(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Advances reconnection until the connection is established.
+    fn poll_connection(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let poll = match self.state {
+                State::Connected(_) => return Ok(Async::Ready(())),
+                State::Backoff(ref mut timeout) => timeout.poll().map(|poll| poll.map(|_| None)),
+                State::Connecting(ref mut connecting) => connecting.poll().map(|poll| poll.map(Some)),
+            };
+
+            self.state = match poll {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) => State::Connecting(connect(&self.addr, &self.params, &self.handle)),
+                Ok(Async::Ready(Some(connection))) => {
+                    if let Some(ref remote_key) = self.params.remote_key {
+                        if connection.remote_key != *remote_key {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("Peer {} has unexpected static key", self.addr),
+                            ));
+                        }
+                    }
+                    info!("Connected to {}", self.addr);
+                    self.failures = 0;
+                    State::Connected(connection.framed)
+                }
+                Err(e) => self.backoff(&e)?,
+            };
+        }
+    }
+
+    fn backoff(&mut self, e: &io::Error) -> Result<State, io::Error> {
+        self.failures += 1;
+        let delay = self.config.backoff(self.failures);
+        warn!("Connection to {} failed: {}, reconnecting in {:?}", self.addr, e, delay);
+        Ok(State::Backoff(Timeout::new(delay, &self.handle)?))
+    }
+
+    fn reconnect(&mut self, e: &io::Error) -> Result<(), io::Error> {
+        self.state = self.backoff(e)?;
+        Ok(())
+    }
+
+    fn framed(&mut self) -> &mut Framed<TcpStream, MessagesCodec> {
+        match self.state {
+            State::Connected(ref mut framed) => framed,
+            _ => panic!("Connection isn't established"),
+        }
+    }
+
+    /// Writes buffered messages to the connection and flushes it.
+    fn poll_flush(&mut self) -> Poll<(), io::Error> {
+        while let Some(msg) = self.outbound.pop_front() {
+            if let AsyncSink::NotReady(msg) = self.framed().start_send(msg)? {
+                self.outbound.push_front(msg);
+                return Ok(Async::NotReady);
+            }
+        }
+        self.framed().poll_complete()
+    }
+}
+
+impl Stream for NoiseConnector {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        loop {
+            try_ready!(self.poll_connection());
+            let poll = self.framed().poll();
+            match poll {
+                Ok(Async::Ready(Some(msg))) => return Ok(Async::Ready(Some(msg))),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) => {
+                    let e = io::Error::new(io::ErrorKind::ConnectionReset, "Peer closed connection");
+                    self.reconnect(&e)?;
+                }
+                Err(e) => self.reconnect(&e)?,
+            }
+        }
+    }
+}
+
+impl Sink for NoiseConnector {
+    type SinkItem = BytesMut;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, msg: BytesMut) -> StartSend<BytesMut, io::Error> {
+        if self.outbound.len() >= self.config.buffer_size {
+            self.poll_complete()?;
+            if self.outbound.len() >= self.config.buffer_size {
+                return Ok(AsyncSink::NotReady(msg));
+            }
+        }
+        self.outbound.push_back(msg);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        loop {
+            try_ready!(self.poll_connection());
+            let poll = self.poll_flush();
+            match poll {
+                Ok(poll) => return Ok(poll),
+                Err(e) => self.reconnect(&e)?,
+            }
+        }
+    }
+}
+
+fn connect(addr: &SocketAddr, params: &HandshakeParams, handle: &Handle) -> ConnectionResult {
+    let params = params.clone();
+    Box::new(TcpStream::connect(addr, handle).and_then(move |stream| NoiseHandshake::connect(&params, stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use connector::{ConnectorConfig, NoiseConnector};
+    use futures::{Future, Sink, Stream};
+    use noise_main::NoiseHandshake;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio_core::net::{Incoming, TcpListener};
+    use tokio_core::reactor::{Core, Timeout};
+    use wrapper::HandshakeParams;
+
+    fn config() -> ConnectorConfig {
+        ConnectorConfig {
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(100),
+            buffer_size: 16,
+        }
+    }
+
+    /// Completes handshake with the next incoming connection and echoes one message.
+    fn echo(incoming: Incoming, params: HandshakeParams) -> Box<Future<Item = (), Error = io::Error>> {
+        Box::new(
+            incoming
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(move |(stream, _)| NoiseHandshake::listen(&params, stream.unwrap().0))
+                .and_then(|framed| framed.into_future().map_err(|(e, _)| e))
+                .and_then(|(msg, framed)| framed.send(msg.unwrap()))
+                .map(drop),
+        )
+    }
+
+    fn send_and_receive(connector: NoiseConnector) -> Box<Future<Item = Option<BytesMut>, Error = io::Error>> {
+        Box::new(
+            connector
+                .send(BytesMut::from("hello"))
+                .and_then(|connector| connector.into_future().map_err(|(e, _)| e))
+                .map(|(msg, _)| msg),
+        )
+    }
+
+    #[test]
+    fn test_connector_failed_handshake() {
+        let addr: SocketAddr = "127.0.0.1:45501".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let params = HandshakeParams::new(1024);
+
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let server_params = params.clone();
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            // The first connection is dropped before the handshake.
+            .and_then(move |(_, incoming)| echo(incoming, server_params));
+
+        let connector = NoiseConnector::new(addr, params, config(), &handle);
+        let client = send_and_receive(connector);
+
+        let (_, msg) = core.run(server.join(client)).unwrap();
+        assert_eq!(&msg.unwrap()[..], b"hello");
+    }
+
+    #[test]
+    fn test_connector_late_listener() {
+        let addr: SocketAddr = "127.0.0.1:45502".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let params = HandshakeParams::new(1024);
+
+        let server_handle = handle.clone();
+        let server_params = params.clone();
+        let server = Timeout::new(Duration::from_millis(300), &handle)
+            .unwrap()
+            .and_then(move |_| echo(TcpListener::bind(&addr, &server_handle).unwrap().incoming(), server_params));
+
+        let connector = NoiseConnector::new(addr, params, config(), &handle);
+        let client = send_and_receive(connector);
+
+        let (_, msg) = core.run(server.join(client)).unwrap();
+        assert_eq!(&msg.unwrap()[..], b"hello");
+    }
+
+    #[test]
+    fn test_connector_unexpected_key() {
+        let addr: SocketAddr = "127.0.0.1:45503".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let params = HandshakeParams::new(1024);
+
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        handle.spawn(echo(listener.incoming(), params.clone()).map_err(drop));
+
+        let mut client_params = params;
+        client_params.set_remote_key(HandshakeParams::new(1024).public_key);
+        let connector = NoiseConnector::new(addr, client_params, config(), &handle);
+
+        let err = core.run(send_and_receive(connector)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_connector_backoff() {
+        let config = ConnectorConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            buffer_size: 16,
+        };
+
+        for _ in 0..100 {
+            let delay = config.backoff(1);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+            let delay = config.backoff(3);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+            let delay = config.backoff(100);
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1));
+        }
+    }
+}
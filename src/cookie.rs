@@ -0,0 +1,201 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stateless cookies which protect the responder from handshake floods.
+//!
+//! When the responder is under load, it answers the first handshake message with
+//! a cookie, which is a MAC of the initiator's address under a periodically rotated
+//! secret. The initiator has to repeat its first message along with the cookie,
+//! only then the responder starts the handshake. Cookies are verified without
+//! any per-peer state and without DH operations.
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand::{thread_rng, Rng};
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const COOKIE_LENGTH: usize = 16;
+const SECRET_LENGTH: usize = 32;
+/// Time after which the cookie secret is replaced, cookies made with the previous
+/// secret are still accepted.
+const SECRET_LIFETIME_SECS: u64 = 120;
+
+#[derive(Debug)]
+struct Secrets {
+    current: [u8; SECRET_LENGTH],
+    previous: Option<[u8; SECRET_LENGTH]>,
+    rotated: Instant,
+}
+
+impl Secrets {
+    fn new() -> Self {
+        let mut current = [0u8; SECRET_LENGTH];
+        thread_rng().fill(&mut current);
+        Secrets {
+            current,
+            previous: None,
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        let mut current = [0u8; SECRET_LENGTH];
+        thread_rng().fill(&mut current);
+        self.previous = Some(self.current);
+        self.current = current;
+        self.rotated = Instant::now();
+    }
+
+    fn rotate_if_expired(&mut self) {
+        if self.rotated.elapsed() >= Duration::from_secs(SECRET_LIFETIME_SECS) {
+            self.rotate();
+        }
+    }
+}
+
+/// Issues and verifies cookies, also tracks the load of the responder.
+///
+/// Checker can be cloned and shared between connections.
+#[derive(Debug, Clone)]
+pub struct CookieChecker {
+    secrets: Arc<Mutex<Secrets>>,
+    load_threshold: usize,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Handshake in flight counted by the checker, the count is decremented on drop.
+#[derive(Debug)]
+pub struct LoadGuard {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl CookieChecker {
+    /// Creates checker which requires cookies once `load_threshold` handshakes are in flight,
+    /// `0` means that cookies are always required.
+    pub fn new(load_threshold: usize) -> Self {
+        CookieChecker {
+            secrets: Arc::new(Mutex::new(Secrets::new())),
+            load_threshold,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn is_under_load(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) >= self.load_threshold
+    }
+
+    /// Counts handshake as in flight until the returned guard is dropped.
+    pub fn start_handshake(&self) -> LoadGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        LoadGuard {
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    pub fn cookie(&self, addr: &SocketAddr) -> [u8; COOKIE_LENGTH] {
+        let mut secrets = self.secrets.lock().unwrap();
+        secrets.rotate_if_expired();
+        mac(&secrets.current, addr)
+    }
+
+    pub fn verify(&self, addr: &SocketAddr, cookie: &[u8]) -> bool {
+        let mut secrets = self.secrets.lock().unwrap();
+        secrets.rotate_if_expired();
+        if cookie.len() != COOKIE_LENGTH {
+            return false;
+        }
+
+        fixed_time_eq(&mac(&secrets.current, addr), cookie)
+            || secrets
+                .previous
+                .map_or(false, |previous| fixed_time_eq(&mac(&previous, addr), cookie))
+    }
+}
+
+fn mac(secret: &[u8], addr: &SocketAddr) -> [u8; COOKIE_LENGTH] {
+    let mut hmac = Hmac::new(Sha256::new(), secret);
+    match addr.ip() {
+        IpAddr::V4(ip) => hmac.input(&ip.octets()),
+        IpAddr::V6(ip) => hmac.input(&ip.octets()),
+    }
+    hmac.input(&[(addr.port() & 0xff) as u8, (addr.port() >> 8) as u8]);
+
+    let mut cookie = [0u8; COOKIE_LENGTH];
+    cookie.copy_from_slice(&hmac.result().code()[..COOKIE_LENGTH]);
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use cookie::CookieChecker;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_cookie_bound_to_address() {
+        let checker = CookieChecker::new(0);
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let cookie = checker.cookie(&addr);
+
+        assert!(checker.verify(&addr, &cookie));
+        assert!(!checker.verify(&"10.0.0.2:5000".parse().unwrap(), &cookie));
+        assert!(!checker.verify(&"10.0.0.1:5001".parse().unwrap(), &cookie));
+        assert!(!checker.verify(&addr, &cookie[1..]));
+
+        let mut tampered = cookie;
+        tampered[0] ^= 1;
+        assert!(!checker.verify(&addr, &tampered));
+
+        // Cookies of the other responder aren't accepted.
+        assert!(!CookieChecker::new(0).verify(&addr, &cookie));
+    }
+
+    #[test]
+    fn test_cookie_secret_rotation() {
+        let checker = CookieChecker::new(0);
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let cookie = checker.cookie(&addr);
+
+        checker.secrets.lock().unwrap().rotate();
+        assert!(checker.verify(&addr, &cookie));
+        assert_ne!(checker.cookie(&addr), cookie);
+
+        checker.secrets.lock().unwrap().rotate();
+        assert!(!checker.verify(&addr, &cookie));
+    }
+
+    #[test]
+    fn test_cookie_load() {
+        let checker = CookieChecker::new(2);
+        assert!(!checker.is_under_load());
+
+        let first = checker.start_handshake();
+        let _second = checker.clone().start_handshake();
+        assert!(checker.is_under_load());
+
+        drop(first);
+        assert!(!checker.is_under_load());
+    }
+}
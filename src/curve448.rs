@@ -0,0 +1,434 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! X448 Diffie-Hellman function (RFC 7748) used for `Noise_*_448_*` protocols.
+//!
+//! Field elements are kept in eight 56-bit limbs, so products fit into `u128`.
+//! The ladder and the field operations don't branch on secret data.
+
+use snow::types::{Dh, Random};
+
+pub const X448_KEY_LENGTH: usize = 56;
+
+const LIMBS: usize = 8;
+const LIMB_BITS: u32 = 56;
+const LIMB_MASK: u64 = (1 << LIMB_BITS) - 1;
+const A24: u64 = 39_081;
+const BASE_POINT_U: u8 = 5;
+
+/// Element of the field modulo `p = 2^448 - 2^224 - 1`.
+///
+/// Limbs are only weakly reduced, i.e. each of them is slightly above 56 bits at most.
+#[derive(Clone, Copy)]
+struct FieldElement([u64; LIMBS]);
+
+impl FieldElement {
+    fn zero() -> Self {
+        FieldElement([0; LIMBS])
+    }
+
+    fn one() -> Self {
+        Self::small(1)
+    }
+
+    fn small(value: u64) -> Self {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = value;
+        FieldElement(limbs)
+    }
+
+    fn from_bytes(bytes: &[u8; X448_KEY_LENGTH]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            for j in 0..7 {
+                *limb |= u64::from(bytes[i * 7 + j]) << (8 * j);
+            }
+        }
+        FieldElement(limbs)
+    }
+
+    fn to_bytes(&self) -> [u8; X448_KEY_LENGTH] {
+        let mut limbs = self.0;
+        // Three passes leave the value below 2^448, which is less than 2p.
+        carry(&mut limbs);
+        carry(&mut limbs);
+        carry(&mut limbs);
+
+        // Subtract p if the value isn't less than it.
+        let mut reduced = [0u64; LIMBS];
+        let mut borrow = 0u64;
+        for i in 0..LIMBS {
+            let p = if i == LIMBS / 2 { LIMB_MASK - 1 } else { LIMB_MASK };
+            let diff = limbs[i].wrapping_sub(p).wrapping_sub(borrow);
+            borrow = diff >> 63;
+            reduced[i] = diff & LIMB_MASK;
+        }
+        let keep = borrow.wrapping_neg();
+        for i in 0..LIMBS {
+            limbs[i] = (limbs[i] & keep) | (reduced[i] & !keep);
+        }
+
+        let mut bytes = [0u8; X448_KEY_LENGTH];
+        for (i, limb) in limbs.iter().enumerate() {
+            for j in 0..7 {
+                bytes[i * 7 + j] = (limb >> (8 * j)) as u8;
+            }
+        }
+        bytes
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        let mut limbs = [0; LIMBS];
+        for i in 0..LIMBS {
+            limbs[i] = self.0[i] + other.0[i];
+        }
+        carry(&mut limbs);
+        FieldElement(limbs)
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        // 2p is added, so limbs don't underflow.
+        let mut limbs = [0; LIMBS];
+        for i in 0..LIMBS {
+            let two_p = if i == LIMBS / 2 { 2 * (LIMB_MASK - 1) } else { 2 * LIMB_MASK };
+            limbs[i] = self.0[i] + two_p - other.0[i];
+        }
+        carry(&mut limbs);
+        FieldElement(limbs)
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        let mut product = [0u128; 2 * LIMBS];
+        for i in 0..LIMBS {
+            for j in 0..LIMBS {
+                product[i + j] += u128::from(self.0[i]) * u128::from(other.0[j]);
+            }
+        }
+
+        // 2^448 = 2^224 + 1 modulo p, high limbs are folded from the top,
+        // so the ones which land into the high half are folded again.
+        for i in (LIMBS..2 * LIMBS).rev() {
+            let high = product[i];
+            product[i - LIMBS] += high;
+            product[i - LIMBS / 2] += high;
+        }
+
+        let mut wide = [0u128; LIMBS];
+        wide.copy_from_slice(&product[..LIMBS]);
+        for i in 0..LIMBS - 1 {
+            wide[i + 1] += wide[i] >> LIMB_BITS;
+            wide[i] &= u128::from(LIMB_MASK);
+        }
+        let top = wide[LIMBS - 1] >> LIMB_BITS;
+        wide[LIMBS - 1] &= u128::from(LIMB_MASK);
+        wide[0] += top;
+        wide[LIMBS / 2] += top;
+
+        let mut limbs = [0; LIMBS];
+        for i in 0..LIMBS {
+            limbs[i] = wide[i] as u64;
+        }
+        carry(&mut limbs);
+        FieldElement(limbs)
+    }
+
+    fn square(&self) -> Self {
+        self.mul(self)
+    }
+
+    /// Inverse by Fermat's little theorem, i.e. `self^(p - 2)`.
+    fn invert(&self) -> Self {
+        // p - 2 = 2^448 - 2^224 - 3, its bits from the top: 223 ones, a zero,
+        // 222 ones, a zero and a one.
+        let mut result = Self::one();
+        for bit in (0..448).rev() {
+            result = result.square();
+            if bit != 224 && bit != 1 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+}
+
+/// Propagates carries between limbs, the carry out of the top limb is folded back.
+fn carry(limbs: &mut [u64; LIMBS]) {
+    for i in 0..LIMBS - 1 {
+        limbs[i + 1] += limbs[i] >> LIMB_BITS;
+        limbs[i] &= LIMB_MASK;
+    }
+    let top = limbs[LIMBS - 1] >> LIMB_BITS;
+    limbs[LIMBS - 1] &= LIMB_MASK;
+    limbs[0] += top;
+    limbs[LIMBS / 2] += top;
+}
+
+/// Swaps `a` and `b` if `swap` is 1.
+fn conditional_swap(a: &mut FieldElement, b: &mut FieldElement, swap: u64) {
+    let mask = swap.wrapping_neg();
+    for i in 0..LIMBS {
+        let t = mask & (a.0[i] ^ b.0[i]);
+        a.0[i] ^= t;
+        b.0[i] ^= t;
+    }
+}
+
+/// Multiplies the point with u-coordinate `point` by the clamped `scalar`.
+pub fn x448(scalar: &[u8; X448_KEY_LENGTH], point: &[u8; X448_KEY_LENGTH]) -> [u8; X448_KEY_LENGTH] {
+    let mut k = *scalar;
+    k[0] &= 252;
+    k[X448_KEY_LENGTH - 1] |= 128;
+
+    let x1 = FieldElement::from_bytes(point);
+    let mut x2 = FieldElement::one();
+    let mut z2 = FieldElement::zero();
+    let mut x3 = x1;
+    let mut z3 = FieldElement::one();
+    let a24 = FieldElement::small(A24);
+    let mut swap = 0;
+
+    for t in (0..448).rev() {
+        let bit = u64::from((k[t / 8] >> (t % 8)) & 1);
+        swap ^= bit;
+        conditional_swap(&mut x2, &mut x3, swap);
+        conditional_swap(&mut z2, &mut z3, swap);
+        swap = bit;
+
+        let a = x2.add(&z2);
+        let aa = a.square();
+        let b = x2.sub(&z2);
+        let bb = b.square();
+        let e = aa.sub(&bb);
+        let c = x3.add(&z3);
+        let d = x3.sub(&z3);
+        let da = d.mul(&a);
+        let cb = c.mul(&b);
+        x3 = da.add(&cb).square();
+        z3 = x1.mul(&da.sub(&cb).square());
+        x2 = aa.mul(&bb);
+        z2 = e.mul(&aa.add(&a24.mul(&e)));
+    }
+    conditional_swap(&mut x2, &mut x3, swap);
+    conditional_swap(&mut z2, &mut z3, swap);
+
+    x2.mul(&z2.invert()).to_bytes()
+}
+
+/// Public key of the `scalar`.
+pub fn x448_base(scalar: &[u8; X448_KEY_LENGTH]) -> [u8; X448_KEY_LENGTH] {
+    let mut base_point = [0u8; X448_KEY_LENGTH];
+    base_point[0] = BASE_POINT_U;
+    x448(scalar, &base_point)
+}
+
+// Curve448 keypair for Noise sessions.
+#[derive(Clone)]
+pub struct Dh448 {
+    privkey: [u8; X448_KEY_LENGTH],
+    pubkey: [u8; X448_KEY_LENGTH],
+}
+
+impl Default for Dh448 {
+    fn default() -> Dh448 {
+        Dh448 {
+            privkey: [0; X448_KEY_LENGTH],
+            pubkey: [0; X448_KEY_LENGTH],
+        }
+    }
+}
+
+impl Dh for Dh448 {
+    fn name(&self) -> &'static str {
+        "448"
+    }
+
+    fn pub_len(&self) -> usize {
+        X448_KEY_LENGTH
+    }
+
+    fn priv_len(&self) -> usize {
+        X448_KEY_LENGTH
+    }
+
+    fn set(&mut self, privkey: &[u8]) {
+        self.privkey.copy_from_slice(&privkey[..X448_KEY_LENGTH]);
+        self.pubkey = x448_base(&self.privkey);
+    }
+
+    fn generate(&mut self, rng: &mut Random) {
+        // Scalar is clamped by `x448` itself.
+        rng.fill_bytes(&mut self.privkey);
+        self.pubkey = x448_base(&self.privkey);
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.pubkey
+    }
+
+    fn privkey(&self) -> &[u8] {
+        &self.privkey
+    }
+
+    /// Fails if the shared secret is all zeros, i.e. the peer sent a low-order point
+    /// (RFC 7748, section 6.2).
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        let mut point = [0u8; X448_KEY_LENGTH];
+        point.copy_from_slice(&pubkey[..X448_KEY_LENGTH]);
+        let shared = x448(&self.privkey, &point);
+        if shared.iter().fold(0, |acc, &byte| acc | byte) == 0 {
+            return Err(());
+        }
+        out[..X448_KEY_LENGTH].copy_from_slice(&shared);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use curve448::{x448, x448_base, Dh448, X448_KEY_LENGTH};
+    use hex;
+    use snow::types::Dh;
+    use sodium_wrapper::SodiumRandom;
+
+    fn key(s: &str) -> [u8; X448_KEY_LENGTH] {
+        let mut key = [0u8; X448_KEY_LENGTH];
+        key.copy_from_slice(&hex::decode(s).unwrap());
+        key
+    }
+
+    #[test]
+    fn test_x448_vectors() {
+        // Test vectors of RFC 7748, section 5.2.
+        let scalar = key(
+            "3d262fddf9ec8e88495266fea19a34d28882acef045104d0d1aae121700a779c\
+             984c24f8cdd78fbff44943eba368f54b29259a4f1c600ad3",
+        );
+        let point = key(
+            "06fce640fa3487bfda5f6cf2d5263f8aad88334cbd07437f020f08f9814dc031\
+             ddbdc38c19c6da2583fa5429db94ada18aa7a7fb4ef8a086",
+        );
+        let expected = key(
+            "ce3e4ff95a60dc6697da1db1d85e6afbdf79b50a2412d7546d5f239fe14fbaad\
+             eb445fc66a01b0779d98223961111e21766282f73dd96b6f",
+        );
+        assert_eq!(&x448(&scalar, &point)[..], &expected[..]);
+
+        let scalar = key(
+            "203d494428b8399352665ddca42f9de8fef600908e0d461cb021f8c538345dd7\
+             7c3e4806e25f46d3315c44e0a5b4371282dd2c8d5be3095f",
+        );
+        let point = key(
+            "0fbcc2f993cd56d3305b0b7d9e55d4c1a8fb5dbb52f8e9a1e9b6201b165d0158\
+             94e56c4d3570bee52fe205e28a78b91cdfbde71ce8d157db",
+        );
+        let expected = key(
+            "884a02576239ff7a2f2f63b2db6a9ff37047ac13568e1e30fe63c4a7ad1b3ee3\
+             a5700df34321d62077e63633c575c1c954514e99da7c179d",
+        );
+        assert_eq!(&x448(&scalar, &point)[..], &expected[..]);
+    }
+
+    #[test]
+    fn test_x448_iterations() {
+        // RFC 7748, section 5.2: k and u start as 5, then k is the result and u is the previous k.
+        let mut k = [0u8; X448_KEY_LENGTH];
+        k[0] = 5;
+        let mut u = k;
+        let mut expected = vec![
+            (1, "3f482c8a9f19b01e6c46ee9711d9dc14fd4bf67af30765c2ae2b846a4d23a8cd\
+                 0db897086239492caf350b51f833868b9bc2b3bca9cf4113"),
+            (1000, "aa3b4749d55b9daf1e5b00288826c467274ce3ebbdd5c17b975e09d4af6c67cf\
+                    10d087202db88286e2b79fceea3ec353ef54faa26e219f38"),
+        ];
+        expected.reverse();
+        for iteration in 1..1001 {
+            let result = x448(&k, &u);
+            u = k;
+            k = result;
+            if expected.last().map(|&(i, _)| i) == Some(iteration) {
+                let (_, value) = expected.pop().unwrap();
+                assert_eq!(&k[..], &key(value)[..], "iteration {}", iteration);
+            }
+        }
+        assert!(expected.is_empty());
+    }
+
+    #[test]
+    fn test_x448_dh() {
+        // RFC 7748, section 6.2.
+        let alice = key(
+            "9a8f4925d1519f5775cf46b04b5800d4ee9ee8bae8bc5565d498c28dd9c9baf5\
+             74a9419744897391006382a6f127ab1d9ac2d8c0a598726b",
+        );
+        let bob = key(
+            "1c306a7ac2a0e2e0990b294470cba339e6453772b075811d8fad0d1d6927c120\
+             bb5ee8972b0d3e21374c9c921b09d1b0366f10b65173992d",
+        );
+        let alice_public = key(
+            "9b08f7cc31b7e3e67d22d5aea121074a273bd2b83de09c63faa73d2c22c5d9bb\
+             c836647241d953d40c5b12da88120d53177f80e532c41fa0",
+        );
+        let bob_public = key(
+            "3eb7a829b0cd20f5bcfc0b599b6feccf6da4627107bdb0d4f345b43027d8b972\
+             fc3e34fb4232a13ca706dcb57aec3dae07bdc1c67bf33609",
+        );
+        let shared = key(
+            "07fff4181ac6cc95ec1c16a94a0f74d12da232ce40a77552281d282bb60c0b56\
+             fd2464c335543936521c24403085d59a449a5037514a879d",
+        );
+
+        assert_eq!(&x448_base(&alice)[..], &alice_public[..]);
+        assert_eq!(&x448_base(&bob)[..], &bob_public[..]);
+        assert_eq!(&x448(&alice, &bob_public)[..], &shared[..]);
+        assert_eq!(&x448(&bob, &alice_public)[..], &shared[..]);
+    }
+
+    #[test]
+    fn test_dh448() {
+        let mut random = SodiumRandom::default();
+        let mut keypair_i = Dh448::default();
+        let mut keypair_r = Dh448::default();
+        keypair_i.generate(&mut random);
+        keypair_r.generate(&mut random);
+
+        let mut output_i = [0u8; X448_KEY_LENGTH];
+        let mut output_r = [0u8; X448_KEY_LENGTH];
+        keypair_i.dh(keypair_r.pubkey(), &mut output_i).unwrap();
+        keypair_r.dh(keypair_i.pubkey(), &mut output_r).unwrap();
+        assert_eq!(&output_i[..], &output_r[..]);
+
+        let mut restored = Dh448::default();
+        restored.set(keypair_i.privkey());
+        assert_eq!(restored.pubkey(), keypair_i.pubkey());
+    }
+
+    #[test]
+    fn test_dh448_low_order_points() {
+        let mut keypair = Dh448::default();
+        keypair.generate(&mut SodiumRandom::default());
+
+        // Points of order 1 and 2 (0 and 1 in u-coordinate) and p - 1, which
+        // is 1 when reduced, give all-zero shared secret with any scalar.
+        let mut p_minus_one = [0xffu8; X448_KEY_LENGTH];
+        p_minus_one[0] = 0xfe;
+        p_minus_one[28] = 0xfe;
+        let mut one = [0u8; X448_KEY_LENGTH];
+        one[0] = 1;
+        for point in &[[0u8; X448_KEY_LENGTH], one, p_minus_one] {
+            let mut output = [0u8; X448_KEY_LENGTH];
+            assert!(keypair.dh(point, &mut output).is_err());
+            assert_eq!(&output[..], &[0u8; X448_KEY_LENGTH][..]);
+        }
+    }
+}
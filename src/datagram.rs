@@ -0,0 +1,600 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Noise session over UDP.
+//!
+//! `XX` handshake messages are retransmitted until the peer answers. After the handshake
+//! each datagram is encrypted with an explicit nonce, so datagrams can be lost or
+//! reordered, replayed ones are dropped by a sliding window.
+//!
+//! Source address of the datagram can be spoofed, so the responder validates it before
+//! the handshake: the first message is answered only with a cookie (see `CookieChecker`),
+//! which is shorter than the message. The initiator repeats the message along with
+//! the cookie, and only then the responder answers it and retransmits the answer.
+
+use bytes::BytesMut;
+use cookie::{CookieChecker, COOKIE_LENGTH};
+use futures::future::done;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Timeout};
+use wrapper::{HandshakeParams, NoiseError, NoiseWrapper, NONCE_LENGTH, TAG_LENGTH};
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Type of the datagram, it is the first byte of each datagram.
+const HANDSHAKE_INIT: u8 = 0;
+const HANDSHAKE_RESPONSE: u8 = 1;
+const HANDSHAKE_FINISH: u8 = 2;
+const TRANSPORT: u8 = 3;
+/// Cookie sent by the responder in reply to the first handshake message.
+const HANDSHAKE_COOKIE: u8 = 4;
+/// First handshake message prefixed with the cookie.
+const HANDSHAKE_INIT_COOKIE: u8 = 5;
+const DATAGRAM_TYPE_LENGTH: usize = 1;
+const COOKIE_DATAGRAM_LENGTH: usize = DATAGRAM_TYPE_LENGTH + COOKIE_LENGTH;
+
+/// Maximal length of the UDP payload over IPv4.
+const MAX_DATAGRAM_LENGTH: usize = 65_507;
+/// Maximal length of the message sent in one datagram.
+pub const MAX_DATAGRAM_PAYLOAD: usize = MAX_DATAGRAM_LENGTH - DATAGRAM_TYPE_LENGTH - NONCE_LENGTH - TAG_LENGTH;
+
+pub type DatagramResult = Box<Future<Item = NoiseDatagram, Error = io::Error>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DatagramConfig {
+    /// Time to wait for the reply before the handshake message is sent again,
+    /// doubled after each retransmission.
+    pub retransmit_timeout: Duration,
+    /// Number of retransmissions after which the handshake fails.
+    pub max_retransmits: u32,
+}
+
+impl Default for DatagramConfig {
+    fn default() -> Self {
+        DatagramConfig {
+            retransmit_timeout: Duration::from_millis(500),
+            max_retransmits: 5,
+        }
+    }
+}
+
+/// Encrypted datagrams exchanged with one peer.
+///
+/// Datagrams sent by the initiator before the responder has received the last
+/// handshake message are lost, as any datagram can be.
+pub struct NoiseDatagram {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    session: NoiseWrapper,
+    recv_buf: Vec<u8>,
+    pending: Option<Vec<u8>>,
+    // Last handshake message of the initiator, which is sent again
+    // until the responder shows that it has been received.
+    finish: Option<Vec<u8>>,
+}
+
+impl NoiseDatagram {
+    /// Runs handshake with `peer` as the initiator.
+    pub fn connect(
+        socket: UdpSocket,
+        peer: SocketAddr,
+        params: &HandshakeParams,
+        config: DatagramConfig,
+        handle: &Handle,
+    ) -> DatagramResult {
+        if params.kem().is_some() {
+            return Box::new(done(Err(kem_unsupported())));
+        }
+        let mut noise = NoiseWrapper::initiator(params);
+        let exchange = noise
+            .write_handshake_msg()
+            .map_err(io::Error::from)
+            .and_then(|(len, buf)| {
+                let init = packet(HANDSHAKE_INIT, &buf[..len]);
+                Exchange::new(socket, peer, init, HANDSHAKE_RESPONSE, config, handle)
+            });
+        let exchange = match exchange {
+            Ok(exchange) => exchange,
+            Err(e) => return Box::new(done(Err(e))),
+        };
+
+        Box::new(exchange.and_then(move |(socket, msg)| {
+            noise.read_handshake_msg(&msg)?;
+            let (len, buf) = noise.write_handshake_msg()?;
+            let finish = packet(HANDSHAKE_FINISH, &buf[..len]);
+            send_packet(&socket, &finish, &peer)?;
+            let session = noise.into_stateless_transport_mode()?;
+            Ok(NoiseDatagram::new(socket, peer, session, Some(finish)))
+        }))
+    }
+
+    /// Waits for the handshake from any peer and runs it as the responder.
+    ///
+    /// First handshake messages which can't be read or come without a valid cookie
+    /// are dropped, so the responder keeps waiting for a valid one. Cookies are issued
+    /// by `HandshakeParams::cookies` regardless of the load, or by a new checker if
+    /// it isn't set.
+    pub fn accept(socket: UdpSocket, params: &HandshakeParams, config: DatagramConfig, handle: &Handle) -> DatagramResult {
+        if params.kem().is_some() {
+            return Box::new(done(Err(kem_unsupported())));
+        }
+        let handle = handle.clone();
+        let first_message = RecvHandshake {
+            socket: Some(socket),
+            params: params.clone(),
+            cookies: params.cookies.clone().unwrap_or_else(|| CookieChecker::new(0)),
+            buf: vec![0u8; MAX_DATAGRAM_LENGTH],
+        };
+
+        Box::new(first_message.and_then(move |(socket, peer, mut noise, response)| -> DatagramResult {
+            let exchange = match Exchange::new(socket, peer, response, HANDSHAKE_FINISH, config, &handle) {
+                Ok(exchange) => exchange,
+                Err(e) => return Box::new(done(Err(e))),
+            };
+
+            Box::new(exchange.and_then(move |(socket, msg)| {
+                noise.read_handshake_msg(&msg)?;
+                let session = noise.into_stateless_transport_mode()?;
+                Ok(NoiseDatagram::new(socket, peer, session, None))
+            }))
+        }))
+    }
+
+    fn new(socket: UdpSocket, peer: SocketAddr, session: NoiseWrapper, finish: Option<Vec<u8>>) -> Self {
+        NoiseDatagram {
+            socket,
+            peer,
+            session,
+            recv_buf: vec![0u8; MAX_DATAGRAM_LENGTH],
+            pending: None,
+            finish,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl Stream for NoiseDatagram {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut self.recv_buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            };
+            if addr != self.peer || len == 0 {
+                continue;
+            }
+
+            match self.recv_buf[0] {
+                TRANSPORT => match self.session.decrypt_datagram(&self.recv_buf[DATAGRAM_TYPE_LENGTH..len]) {
+                    Ok(msg) => {
+                        // Responder can't encrypt datagrams before it has finished the handshake.
+                        self.finish = None;
+                        return Ok(Async::Ready(Some(BytesMut::from(msg))));
+                    }
+                    Err(e) => debug!("Dropped datagram from {}: {}", addr, e),
+                },
+                // Responder hasn't received the last handshake message yet.
+                HANDSHAKE_RESPONSE => {
+                    if let Some(ref finish) = self.finish {
+                        send_packet(&self.socket, finish, &self.peer)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Sink for NoiseDatagram {
+    type SinkItem = BytesMut;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, msg: BytesMut) -> StartSend<BytesMut, io::Error> {
+        if self.pending.is_some() {
+            self.poll_complete()?;
+            if self.pending.is_some() {
+                return Ok(AsyncSink::NotReady(msg));
+            }
+        }
+
+        if msg.len() > MAX_DATAGRAM_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Datagram payload is too long: {}", msg.len()),
+            ));
+        }
+        let datagram = self.session.encrypt_datagram(&msg)?;
+        let mut packet = Vec::with_capacity(DATAGRAM_TYPE_LENGTH + datagram.len());
+        packet.push(TRANSPORT);
+        packet.extend_from_slice(&datagram);
+        self.pending = Some(packet);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if let Some(packet) = self.pending.take() {
+            match self.socket.send_to(&packet, &self.peer) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.pending = Some(packet);
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Waits for the valid first handshake message from any peer with validated address,
+/// returns the responder session along with the response to it.
+struct RecvHandshake {
+    socket: Option<UdpSocket>,
+    params: HandshakeParams,
+    cookies: CookieChecker,
+    buf: Vec<u8>,
+}
+
+impl Future for RecvHandshake {
+    type Item = (UdpSocket, SocketAddr, NoiseWrapper, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            let received = self
+                .socket
+                .as_ref()
+                .expect("RecvHandshake polled after completion")
+                .recv_from(&mut self.buf);
+            let (len, addr) = match received {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            };
+
+            // Datagrams shorter than the cookie reply aren't answered, so the reply never
+            // amplifies the traffic sent with a spoofed address.
+            if len < COOKIE_DATAGRAM_LENGTH {
+                continue;
+            }
+            match self.buf[0] {
+                HANDSHAKE_INIT => {
+                    let cookie = packet(HANDSHAKE_COOKIE, &self.cookies.cookie(&addr));
+                    if let Err(e) = send_packet(self.socket.as_ref().unwrap(), &cookie, &addr) {
+                        debug!("Unable to send cookie to {}: {}", addr, e);
+                    }
+                    continue;
+                }
+                HANDSHAKE_INIT_COOKIE => {}
+                _ => continue,
+            }
+
+            let msg = &self.buf[DATAGRAM_TYPE_LENGTH..len];
+            if !self.cookies.verify(&addr, &msg[..COOKIE_LENGTH]) {
+                debug!("Dropped handshake datagram with invalid cookie from {}", addr);
+                continue;
+            }
+            let mut noise = NoiseWrapper::responder(&self.params);
+            match respond(&mut noise, &msg[COOKIE_LENGTH..]) {
+                Ok(response) => return Ok(Async::Ready((self.socket.take().unwrap(), addr, noise, response))),
+                Err(e) => debug!("Dropped handshake datagram from {}: {}", addr, e),
+            }
+        }
+    }
+}
+
+/// Sends handshake message and waits for the reply of the `expected` type,
+/// the message is retransmitted while there is no reply.
+///
+/// If the first handshake message is answered with the cookie, the message
+/// is sent again along with the cookie.
+struct Exchange {
+    socket: Option<UdpSocket>,
+    peer: SocketAddr,
+    packet: Vec<u8>,
+    expected: u8,
+    buf: Vec<u8>,
+    timer: Timeout,
+    timeout: Duration,
+    retransmits_left: u32,
+}
+
+impl Exchange {
+    fn new(
+        socket: UdpSocket,
+        peer: SocketAddr,
+        packet: Vec<u8>,
+        expected: u8,
+        config: DatagramConfig,
+        handle: &Handle,
+    ) -> io::Result<Self> {
+        send_packet(&socket, &packet, &peer)?;
+        Ok(Exchange {
+            timer: Timeout::new(config.retransmit_timeout, handle)?,
+            socket: Some(socket),
+            peer,
+            packet,
+            expected,
+            buf: vec![0u8; MAX_DATAGRAM_LENGTH],
+            timeout: config.retransmit_timeout,
+            retransmits_left: config.max_retransmits,
+        })
+    }
+}
+
+impl Future for Exchange {
+    type Item = (UdpSocket, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            let received = self
+                .socket
+                .as_ref()
+                .expect("Exchange polled after completion")
+                .recv_from(&mut self.buf);
+            let (len, addr) = match received {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+
+            if addr != self.peer || len == 0 {
+                continue;
+            }
+            if self.buf[0] == self.expected {
+                let msg = self.buf[DATAGRAM_TYPE_LENGTH..len].to_vec();
+                return Ok(Async::Ready((self.socket.take().unwrap(), msg)));
+            }
+            if self.buf[0] == HANDSHAKE_COOKIE && len == COOKIE_DATAGRAM_LENGTH && self.packet[0] == HANDSHAKE_INIT {
+                let mut init = packet(HANDSHAKE_INIT_COOKIE, &self.buf[DATAGRAM_TYPE_LENGTH..len]);
+                init.extend_from_slice(&self.packet[DATAGRAM_TYPE_LENGTH..]);
+                send_packet(self.socket.as_ref().unwrap(), &init, &self.peer)?;
+                self.packet = init;
+            }
+        }
+
+        while self.timer.poll()?.is_ready() {
+            if self.retransmits_left == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Peer {} hasn't answered the handshake", self.peer),
+                ));
+            }
+            self.retransmits_left -= 1;
+            send_packet(self.socket.as_ref().unwrap(), &self.packet, &self.peer)?;
+            self.timeout *= 2;
+            self.timer.reset(Instant::now() + self.timeout);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+fn respond(noise: &mut NoiseWrapper, msg: &[u8]) -> Result<Vec<u8>, NoiseError> {
+    noise.read_handshake_msg(msg)?;
+    let (len, buf) = noise.write_handshake_msg()?;
+    Ok(packet(HANDSHAKE_RESPONSE, &buf[..len]))
+}
+
+fn kem_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "KEM handshake isn't supported over datagrams")
+}
+
+fn packet(datagram_type: u8, msg: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(DATAGRAM_TYPE_LENGTH + msg.len());
+    packet.push(datagram_type);
+    packet.extend_from_slice(msg);
+    packet
+}
+
+/// Sends the packet, if the socket isn't ready the packet is lost as any other datagram.
+fn send_packet(socket: &UdpSocket, packet: &[u8], peer: &SocketAddr) -> io::Result<()> {
+    match socket.send_to(packet, peer) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use datagram::{DatagramConfig, NoiseDatagram, COOKIE_DATAGRAM_LENGTH, HANDSHAKE_COOKIE, HANDSHAKE_INIT};
+    use futures::stream::iter_ok;
+    use futures::{Future, Sink, Stream};
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio_core::net::UdpSocket;
+    use tokio_core::reactor::{Core, Timeout};
+    use wrapper::HandshakeParams;
+
+    fn config() -> DatagramConfig {
+        DatagramConfig {
+            retransmit_timeout: Duration::from_millis(100),
+            max_retransmits: 5,
+        }
+    }
+
+    #[test]
+    fn test_datagram_exchange() {
+        let responder_addr: SocketAddr = "127.0.0.1:45701".parse().unwrap();
+        let initiator_addr: SocketAddr = "127.0.0.1:45702".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let params = HandshakeParams::new(1024);
+        let responder = NoiseDatagram::accept(
+            UdpSocket::bind(&responder_addr, &handle).unwrap(),
+            &params,
+            config(),
+            &handle,
+        );
+        let initiator = NoiseDatagram::connect(
+            UdpSocket::bind(&initiator_addr, &handle).unwrap(),
+            responder_addr,
+            &params,
+            config(),
+            &handle,
+        );
+        let (mut responder, initiator) = core.run(responder.join(initiator)).unwrap();
+        assert_eq!(responder.peer_addr(), initiator_addr);
+
+        let messages: Vec<_> = (0..3u8).map(|i| BytesMut::from(vec![i; 100])).collect();
+        let send = initiator.send_all(iter_ok::<_, io::Error>(messages.clone()));
+        let ((mut initiator, _), received) = core.run(send.join(responder.by_ref().take(3).collect())).unwrap();
+        assert_eq!(received, messages);
+
+        let reply = BytesMut::from(vec![7; 10]);
+        let send = responder.send(reply.clone());
+        let (_, received) = core.run(send.join(initiator.by_ref().take(1).collect())).unwrap();
+        assert_eq!(received, vec![reply]);
+    }
+
+    #[test]
+    fn test_datagram_handshake_retransmission() {
+        let responder_addr: SocketAddr = "127.0.0.1:45703".parse().unwrap();
+        let initiator_addr: SocketAddr = "127.0.0.1:45704".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let params = HandshakeParams::new(1024);
+        // First handshake message is sent before the responder is bound and gets lost.
+        let initiator = NoiseDatagram::connect(
+            UdpSocket::bind(&initiator_addr, &handle).unwrap(),
+            responder_addr,
+            &params,
+            config(),
+            &handle,
+        );
+        let responder_params = params.clone();
+        let responder_handle = handle.clone();
+        let responder = Timeout::new(Duration::from_millis(150), &handle)
+            .unwrap()
+            .and_then(move |_| {
+                let socket = UdpSocket::bind(&responder_addr, &responder_handle).unwrap();
+                NoiseDatagram::accept(socket, &responder_params, config(), &responder_handle)
+            });
+
+        let (initiator, responder) = core.run(initiator.join(responder)).unwrap();
+        let receive = responder.into_future().map_err(|(e, _)| e);
+        let (_, (received, _)) = core.run(initiator.send(BytesMut::from(vec![1; 10])).join(receive)).unwrap();
+        assert_eq!(received, Some(BytesMut::from(vec![1; 10])));
+    }
+
+    #[test]
+    fn test_datagram_handshake_timeout() {
+        let initiator_addr: SocketAddr = "127.0.0.1:45705".parse().unwrap();
+        let responder_addr: SocketAddr = "127.0.0.1:45706".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let config = DatagramConfig {
+            retransmit_timeout: Duration::from_millis(10),
+            max_retransmits: 2,
+        };
+        let initiator = NoiseDatagram::connect(
+            UdpSocket::bind(&initiator_addr, &handle).unwrap(),
+            responder_addr,
+            &HandshakeParams::new(1024),
+            config,
+            &handle,
+        );
+        let err = core.run(initiator).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_datagram_malformed_handshake_skipped() {
+        let responder_addr: SocketAddr = "127.0.0.1:45707".parse().unwrap();
+        let initiator_addr: SocketAddr = "127.0.0.1:45708".parse().unwrap();
+        let attacker_addr: SocketAddr = "127.0.0.1:45709".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let params = HandshakeParams::new(1024);
+        let responder = NoiseDatagram::accept(
+            UdpSocket::bind(&responder_addr, &handle).unwrap(),
+            &params,
+            config(),
+            &handle,
+        );
+        // Too short to contain the ephemeral key, so the responder can't read it.
+        let attacker = UdpSocket::bind(&attacker_addr, &handle).unwrap();
+        attacker.send_to(&[HANDSHAKE_INIT, 1, 2, 3], &responder_addr).unwrap();
+
+        let initiator = NoiseDatagram::connect(
+            UdpSocket::bind(&initiator_addr, &handle).unwrap(),
+            responder_addr,
+            &params,
+            config(),
+            &handle,
+        );
+        let (responder, initiator) = core.run(responder.join(initiator)).unwrap();
+        assert_eq!(responder.peer_addr(), initiator_addr);
+
+        let receive = responder.into_future().map_err(|(e, _)| e);
+        let (_, (received, _)) = core.run(initiator.send(BytesMut::from(vec![1; 10])).join(receive)).unwrap();
+        assert_eq!(received, Some(BytesMut::from(vec![1; 10])));
+    }
+
+    #[test]
+    fn test_datagram_spoofed_handshake_not_answered() {
+        let responder_addr: SocketAddr = "127.0.0.1:45710".parse().unwrap();
+        let initiator_addr: SocketAddr = "127.0.0.1:45711".parse().unwrap();
+        let victim_addr: SocketAddr = "127.0.0.1:45712".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let params = HandshakeParams::new(1024);
+        let responder = NoiseDatagram::accept(
+            UdpSocket::bind(&responder_addr, &handle).unwrap(),
+            &params,
+            config(),
+            &handle,
+        );
+        // Handshake message which source address isn't validated, as if it was spoofed.
+        let victim = UdpSocket::bind(&victim_addr, &handle).unwrap();
+        let mut init = vec![HANDSHAKE_INIT];
+        init.extend_from_slice(&[0u8; 32]);
+        victim.send_to(&init, &responder_addr).unwrap();
+
+        let initiator = NoiseDatagram::connect(
+            UdpSocket::bind(&initiator_addr, &handle).unwrap(),
+            responder_addr,
+            &params,
+            config(),
+            &handle,
+        );
+        let (responder, _) = core.run(responder.join(initiator)).unwrap();
+        assert_eq!(responder.peer_addr(), initiator_addr);
+
+        // Victim only gets the cookie, which is shorter than the handshake message.
+        let (_, buf, len, addr) = core.run(victim.recv_dgram(vec![0u8; 1024])).unwrap();
+        assert_eq!(addr, responder_addr);
+        assert_eq!(buf[0], HANDSHAKE_COOKIE);
+        assert_eq!(len, COOKIE_DATAGRAM_LENGTH);
+        assert!(len < init.len());
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keepalive of idle transport connections.
+//!
+//! When nothing has been received from the peer for the idle interval, an encrypted
+//! ping is sent, which the peer answers with pong. If nothing has been received
+//! for the timeout, the peer is considered dead and the stream fails with
+//! `io::ErrorKind::TimedOut` error.
+
+use bytes::BytesMut;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use noise_codec::{Frame, FramesCodec, MessagesCodec};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::codec::Framed;
+use tokio_io::io::shutdown;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveConfig {
+    /// Time without incoming frames after which ping is sent.
+    pub idle_interval: Duration,
+    /// Time without incoming frames after which the peer is considered dead.
+    pub timeout: Duration,
+}
+
+impl KeepaliveConfig {
+    pub fn new(idle_interval: Duration, timeout: Duration) -> Self {
+        KeepaliveConfig {
+            idle_interval,
+            timeout,
+        }
+    }
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig::new(Duration::from_secs(30), Duration::from_secs(90))
+    }
+}
+
+/// Transport connection which keeps itself alive with ping/pong control frames.
+///
+/// Timers are driven by polling the `Stream` side, so it has to be polled
+/// even if no messages are expected.
+pub struct Keepalive<T> {
+    framed: Framed<T, FramesCodec>,
+    config: KeepaliveConfig,
+    idle_timer: Timeout,
+    dead_timer: Timeout,
+    // Control frames which haven't fit into the write buffer yet.
+    pending: VecDeque<Frame>,
+}
+
+impl<T: AsyncRead + AsyncWrite> Keepalive<T> {
+    pub fn new(framed: Framed<T, MessagesCodec>, config: KeepaliveConfig, handle: &Handle) -> io::Result<Self> {
+        // Bytes which have already been read from the socket are kept.
+        let (parts, codec) = framed.into_parts_and_codec();
+        Ok(Keepalive {
+            framed: Framed::from_parts(parts, codec.into_frames()),
+            config,
+            idle_timer: Timeout::new(config.idle_interval, handle)?,
+            dead_timer: Timeout::new(config.timeout, handle)?,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Sends close frame with the given reason code and shuts down the connection.
+    pub fn close(self, reason: u16) -> Box<Future<Item = T, Error = io::Error>>
+    where
+        T: 'static,
+    {
+        Box::new(
+            self.framed
+                .send(Frame::Close(reason))
+                .and_then(|framed| shutdown(framed.into_inner())),
+        )
+    }
+
+    fn on_received(&mut self) {
+        let now = Instant::now();
+        self.idle_timer.reset(now + self.config.idle_interval);
+        self.dead_timer.reset(now + self.config.timeout);
+    }
+
+    fn send_control(&mut self) -> Poll<(), io::Error> {
+        while let Some(frame) = self.pending.pop_front() {
+            if let AsyncSink::NotReady(frame) = self.framed.start_send(frame)? {
+                self.pending.push_front(frame);
+                return Ok(Async::NotReady);
+            }
+        }
+        self.framed.poll_complete()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Stream for Keepalive<T> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        loop {
+            match self.framed.poll()? {
+                Async::Ready(Some(frame)) => {
+                    self.on_received();
+                    match frame {
+                        Frame::Message(msg) => return Ok(Async::Ready(Some(msg))),
+                        Frame::Ping => {
+                            self.pending.push_back(Frame::Pong);
+                            self.send_control()?;
+                        }
+                        Frame::Pong => {}
+                        Frame::Close(_) => return Ok(Async::Ready(None)),
+                    }
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => break,
+            }
+        }
+
+        if self.dead_timer.poll()?.is_ready() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Peer hasn't responded within keepalive timeout",
+            ));
+        }
+        // Ping is repeated every idle interval until the peer responds or the timeout expires.
+        while self.idle_timer.poll()?.is_ready() {
+            self.idle_timer.reset(Instant::now() + self.config.idle_interval);
+            self.pending.push_back(Frame::Ping);
+        }
+        self.send_control()?;
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Sink for Keepalive<T> {
+    type SinkItem = BytesMut;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, msg: BytesMut) -> StartSend<BytesMut, io::Error> {
+        if !self.pending.is_empty() && self.send_control()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(msg));
+        }
+        match self.framed.start_send(Frame::Message(msg))? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(Frame::Message(msg)) => Ok(AsyncSink::NotReady(msg)),
+            AsyncSink::NotReady(_) => unreachable!(),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.send_control()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::{Future, Sink, Stream};
+    use keepalive::{Keepalive, KeepaliveConfig};
+    use noise_main::NoiseHandshake;
+    use std::io;
+    use std::time::Duration;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::{Core, Timeout};
+    use wrapper::HandshakeParams;
+
+    fn config() -> KeepaliveConfig {
+        KeepaliveConfig::new(Duration::from_millis(50), Duration::from_millis(200))
+    }
+
+    #[test]
+    fn test_keepalive_idle_connection() {
+        let addr = "127.0.0.1:45301".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let params = HandshakeParams::new(1024);
+
+        // Server stays silent for two timeouts, pongs keep the client alive.
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let server_handle = handle.clone();
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| NoiseHandshake::listen(&params, stream.unwrap().0))
+            .and_then(move |framed| {
+                let (sink, stream) = Keepalive::new(framed, config(), &server_handle).unwrap().split();
+                server_handle.spawn(stream.for_each(|_| Ok(())).map_err(|e| panic!("{}", e)));
+                Timeout::new(Duration::from_millis(400), &server_handle)
+                    .unwrap()
+                    .and_then(move |_| sink.send(BytesMut::from("hello")))
+            });
+
+        let client_handle = handle.clone();
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(|stream| NoiseHandshake::send(&params, stream))
+            .and_then(move |framed| Keepalive::new(framed, config(), &client_handle))
+            .and_then(|keepalive| keepalive.into_future().map_err(|(e, _)| e));
+
+        let (_, (msg, _)) = core.run(server.join(client)).unwrap();
+        assert_eq!(&msg.unwrap()[..], b"hello");
+    }
+
+    #[test]
+    fn test_keepalive_dead_peer() {
+        let addr = "127.0.0.1:45302".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let params = HandshakeParams::new(1024);
+
+        // Server without keepalive never answers pings.
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let server_handle = handle.clone();
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| NoiseHandshake::listen(&params, stream.unwrap().0))
+            .and_then(move |framed| {
+                Timeout::new(Duration::from_secs(1), &server_handle)
+                    .unwrap()
+                    .map(move |_| drop(framed))
+            });
+        handle.spawn(server.map_err(|e| panic!("{}", e)));
+
+        let client_handle = handle.clone();
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(|stream| NoiseHandshake::send(&params, stream))
+            .and_then(move |framed| Keepalive::new(framed, config(), &client_handle))
+            .and_then(|keepalive| keepalive.into_future().map_err(|(e, _)| e));
+
+        let err = core.run(client).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hook for mixing the secret of a key encapsulation mechanism into the `XX` handshake.
+//!
+//! The initiator sends an ephemeral KEM public key in the payload of the first `XX` message,
+//! the responder encapsulates a secret to it and sends the ciphertext in the encrypted payload
+//! of the second message. The secret is then used as `psk3`, so transport keys depend on both
+//! Diffie-Hellman and KEM secrets.
+//!
+//! The hook by itself gives no post-quantum confidentiality: it is only as strong as
+//! the plugged in KEM. The crate doesn't ship a post-quantum KEM, the only implementation
+//! is `SodiumTestKem25519`, which is there for tests. A post-quantum KEM, e.g. Kyber,
+//! has to be provided by the application by implementing `Kem` trait.
+//!
+//! The secret is mixed as a psk rather than with the `hfs` modifier, because `hfs` needs
+//! `e1`/`ekem1` tokens and a KEM primitive in the Noise state machine and the crypto
+//! resolver, which `snow` doesn't support, while psk modifiers work with the stock patterns.
+//! `psk3` is the only position where the secret is known to both peers: it arrives with
+//! the second message and is mixed in after the tokens of the third one. Compared with
+//! `hfs` this means:
+//!
+//! - the KEM public key is sent in the clear, as the first message payload isn't encrypted;
+//! - static keys of both peers are protected only by Diffie-Hellman, the KEM secret
+//!   secures the third message payload and the transport keys;
+//! - a peer with another KEM fails when the third message is decrypted.
+
+use wrapper::NoiseError;
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+pub const KEM_SECRET_LENGTH: usize = 32;
+
+pub trait Kem: Send + Sync {
+    /// Name of the KEM, bound into the handshake.
+    fn name(&self) -> &'static str;
+
+    /// Generates ephemeral keypair, returns public and secret keys.
+    fn generate_keypair(&self) -> (Vec<u8>, Vec<u8>);
+
+    /// Encapsulates a fresh secret to `public_key`, returns ciphertext and the secret.
+    fn encapsulate(&self, public_key: &[u8]) -> Result<(Vec<u8>, [u8; KEM_SECRET_LENGTH]), NoiseError>;
+
+    /// Recovers the secret encapsulated in `ciphertext`.
+    fn decapsulate(&self, secret_key: &[u8], ciphertext: &[u8]) -> Result<[u8; KEM_SECRET_LENGTH], NoiseError>;
+}
+
+/// KEM whose secret is mixed into the handshake as `psk3`, can be shared between handshakes.
+/// It is as strong as the wrapped KEM, see the module docs.
+#[derive(Clone)]
+pub struct SharedKem(Arc<Kem>);
+
+impl SharedKem {
+    pub fn new<K: Kem + 'static>(kem: K) -> Self {
+        SharedKem(Arc::new(kem))
+    }
+}
+
+impl Deref for SharedKem {
+    type Target = Kem;
+
+    fn deref(&self) -> &Kem {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for SharedKem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedKem({})", self.name())
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use hex;
+use negotiation::Curve;
+use sodium_wrapper::SodiumRandom;
+use wrapper::{resolver, NoiseError};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Static keypair of the node on one of the supported curves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyPair {
+    pub curve: Curve,
+    pub public_key: Vec<u8>,
+    pub secret_key: Vec<u8>,
+}
+
+impl KeyPair {
+    /// Generates fresh keypair on `curve`.
+    pub fn generate(curve: Curve) -> Self {
+        let mut dh = resolver()
+            .resolve_dh(&curve.choice())
+            .expect("Curve isn't supported by the crypto resolver");
+        dh.generate(&mut SodiumRandom::default());
+        KeyPair {
+            curve,
+            public_key: dh.pubkey().to_vec(),
+            secret_key: dh.privkey().to_vec(),
+        }
+    }
+
+    /// Restores keypair from the secret key.
+    pub fn from_secret_key(curve: Curve, secret_key: &[u8]) -> Result<Self, NoiseError> {
+        let mut dh = resolver()
+            .resolve_dh(&curve.choice())
+            .ok_or_else(|| NoiseError::new(format!("Curve {} isn't supported", curve.name())))?;
+        if secret_key.len() != dh.priv_len() {
+            return Err(NoiseError::new(format!(
+                "Secret key of curve {} should be {} bytes long, got {}",
+                curve.name(),
+                dh.priv_len(),
+                secret_key.len()
+            )));
+        }
+        dh.set(secret_key);
+        Ok(KeyPair {
+            curve,
+            public_key: dh.pubkey().to_vec(),
+            secret_key: secret_key.to_vec(),
+        })
+    }
+
+    /// Loads keypair from the file at `path`.
+    ///
+    /// The file contains name of the curve and hex encoded secret key, e.g. `448 9a8f...`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed key file");
+
+        let mut parts = contents.split_whitespace();
+        let curve = parts.next().and_then(Curve::from_name).ok_or_else(malformed)?;
+        let secret_key = parts
+            .next()
+            .and_then(|key| hex::decode(key).ok())
+            .ok_or_else(malformed)?;
+        if parts.next().is_some() {
+            return Err(malformed());
+        }
+        Self::from_secret_key(curve, &secret_key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// Saves keypair to the file at `path`, on Unix the file is readable only by the owner.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(path)?;
+        writeln!(file, "{} {}", self.curve.name(), hex::encode(&self.secret_key))?;
+        file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keypair::KeyPair;
+    use negotiation::Curve;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_keypair_file() {
+        for &(curve, key_length) in &[(Curve::Curve25519, 32), (Curve::Curve448, 56)] {
+            let path = env::temp_dir().join(format!("noise_test_keypair_{}", curve.name()));
+            let keypair = KeyPair::generate(curve);
+            assert_eq!(keypair.public_key.len(), key_length);
+            assert_eq!(keypair.secret_key.len(), key_length);
+
+            keypair.save(&path).unwrap();
+            assert_eq!(KeyPair::load(&path).unwrap(), keypair);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_malformed_keypair_file() {
+        let path = env::temp_dir().join("noise_test_keypair_malformed");
+        let secret_key = KeyPair::generate(Curve::Curve448).secret_key;
+        let contents = vec![
+            String::new(),
+            format!("25519 {}", ::hex::encode(&secret_key)),
+            format!("449 {}", ::hex::encode(&secret_key)),
+            "448 not-hex".to_owned(),
+            format!("448 {} extra", ::hex::encode(&secret_key)),
+        ];
+        for contents in contents {
+            fs::File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+            assert!(KeyPair::load(&path).is_err(), "{}", contents);
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert!(KeyPair::from_secret_key(Curve::Curve448, &secret_key).is_ok());
+        assert!(KeyPair::from_secret_key(Curve::Curve25519, &secret_key).is_err());
+    }
+}
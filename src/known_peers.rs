@@ -0,0 +1,233 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use hex;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Identifier under which static key of the remote peer is stored.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerId {
+    Address(SocketAddr),
+    Node(String),
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerId::Address(ref addr) => write!(f, "addr:{}", addr),
+            PeerId::Node(ref id) => write!(f, "node:{}", id),
+        }
+    }
+}
+
+impl PeerId {
+    /// Node ids are stored as one word of the line, so they can't be empty
+    /// or contain whitespace and control characters.
+    fn is_valid(&self) -> bool {
+        match *self {
+            PeerId::Address(_) => true,
+            PeerId::Node(ref id) => !id.is_empty() && !id.chars().any(|c| c.is_whitespace() || c.is_control()),
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        if s.starts_with("addr:") {
+            s["addr:".len()..].parse().ok().map(PeerId::Address)
+        } else if s.starts_with("node:") {
+            Some(PeerId::Node(s["node:".len()..].to_owned()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Static keys of the peers we have already completed handshake with.
+///
+/// Used to choose one round trip handshake pattern on reconnect.
+/// The store can be cloned and shared between connections, if it is opened
+/// from a file, every update is written back to that file.
+#[derive(Debug, Clone, Default)]
+pub struct KnownPeers {
+    peers: Arc<RwLock<HashMap<PeerId, Vec<u8>>>>,
+    path: Option<PathBuf>,
+}
+
+impl KnownPeers {
+    /// Creates empty in-memory store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens store backed by the file at `path`, the file is created on the first update.
+    ///
+    /// Each line of the file contains peer identifier and hex encoded static key.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut peers = HashMap::new();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let mut parts = line.split_whitespace();
+                let peer = parts.next().and_then(PeerId::parse);
+                let key = parts.next().and_then(|key| hex::decode(key).ok());
+                match (peer, key) {
+                    (Some(peer), Some(key)) => {
+                        peers.insert(peer, key);
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Malformed known peers entry: {}", line),
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(KnownPeers {
+            peers: Arc::new(RwLock::new(peers)),
+            path: Some(path),
+        })
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<Vec<u8>> {
+        self.peers.read().unwrap().get(peer).cloned()
+    }
+
+    /// Remembers static key of the `peer`, replacing the previous one.
+    ///
+    /// Fails if node id of the `peer` is empty or contains whitespace or control characters.
+    pub fn insert(&self, peer: PeerId, remote_key: Vec<u8>) -> io::Result<()> {
+        if !peer.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid peer identifier: {:?}", peer),
+            ));
+        }
+
+        let mut peers = self.peers.write().unwrap();
+        if peers.get(&peer) == Some(&remote_key) {
+            return Ok(());
+        }
+
+        peers.insert(peer, remote_key);
+        match self.path {
+            Some(ref path) => Self::save(path, &peers),
+            None => Ok(()),
+        }
+    }
+
+    pub fn remove(&self, peer: &PeerId) -> io::Result<()> {
+        let mut peers = self.peers.write().unwrap();
+        if peers.remove(peer).is_none() {
+            return Ok(());
+        }
+
+        match self.path {
+            Some(ref path) => Self::save(path, &peers),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes peers into a temporary file next to the store and renames it over the store,
+    /// so a crash in the middle of saving doesn't leave the store truncated.
+    fn save(path: &Path, peers: &HashMap<PeerId, Vec<u8>>) -> io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        {
+            let mut file = File::create(&tmp_path)?;
+            for (peer, key) in peers {
+                writeln!(file, "{} {}", peer, hex::encode(key))?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use known_peers::{KnownPeers, PeerId};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_known_peers_in_memory() {
+        let peers = KnownPeers::new();
+        let addr = PeerId::Address("127.0.0.1:8000".parse().unwrap());
+        let node = PeerId::Node("node-1".to_owned());
+
+        assert_eq!(peers.get(&addr), None);
+        peers.insert(addr.clone(), vec![1; 32]).unwrap();
+        peers.insert(node.clone(), vec![2; 32]).unwrap();
+        assert_eq!(peers.get(&addr), Some(vec![1; 32]));
+        assert_eq!(peers.get(&node), Some(vec![2; 32]));
+
+        peers.remove(&addr).unwrap();
+        assert_eq!(peers.get(&addr), None);
+    }
+
+    #[test]
+    fn test_known_peers_file() {
+        let path = env::temp_dir().join("noise_test_known_peers");
+        let _ = fs::remove_file(&path);
+
+        let addr = PeerId::Address("127.0.0.1:8000".parse().unwrap());
+        let node = PeerId::Node("node-1".to_owned());
+        {
+            let peers = KnownPeers::open(&path).unwrap();
+            peers.insert(addr.clone(), vec![1; 32]).unwrap();
+            peers.insert(node.clone(), vec![2; 32]).unwrap();
+        }
+
+        let peers = KnownPeers::open(&path).unwrap();
+        assert_eq!(peers.get(&addr), Some(vec![1; 32]));
+        assert_eq!(peers.get(&node), Some(vec![2; 32]));
+        assert!(!path.with_file_name("noise_test_known_peers.tmp").exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_known_peers_invalid_node_id() {
+        let path = env::temp_dir().join("noise_test_known_peers_invalid");
+        let _ = fs::remove_file(&path);
+
+        let peers = KnownPeers::open(&path).unwrap();
+        for id in &["", "node 1", "node\n1", "node\t1"] {
+            assert!(peers.insert(PeerId::Node(id.to_string()), vec![1; 32]).is_err());
+        }
+        peers.insert(PeerId::Node("node-1".to_owned()), vec![1; 32]).unwrap();
+
+        // Rejected ids haven't made the store unreadable.
+        let peers = KnownPeers::open(&path).unwrap();
+        assert_eq!(peers.get(&PeerId::Node("node-1".to_owned())), Some(vec![1; 32]));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
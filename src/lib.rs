@@ -1,6 +1,8 @@
 extern crate byteorder;
 extern crate bytes;
 extern crate clap;
+extern crate crypto;
+#[macro_use]
 extern crate futures;
 #[macro_use]
 extern crate lazy_static;
@@ -19,6 +21,7 @@ extern crate env_logger;
 #[macro_use]
 extern crate log;
 extern crate exonum_sodiumoxide as sodiumoxide;
+extern crate hex;
 extern crate rand;
 
 use futures::future::Future;
@@ -36,7 +39,23 @@ use tokio::executor::current_thread;
 use tokio_io::codec::Framed;
 
 pub mod wrapper;
+pub mod connector;
+pub mod cookie;
+pub mod curve448;
+pub mod datagram;
+pub mod keepalive;
+pub mod kem;
+pub mod keypair;
+pub mod known_peers;
+pub mod limiter;
+pub mod listener;
+pub mod multiplexer;
+pub mod negotiation;
 pub mod noise_main;
 pub mod noise_codec;
+pub mod obfuscation;
+pub mod padding;
+pub mod replay;
 pub mod sodium_wrapper;
+pub mod streaming;
 
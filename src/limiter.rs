@@ -0,0 +1,300 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limits of the incoming handshakes, checked before any crypto is done.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Maximal number of token buckets, the oldest bucket is dropped to make room for a new one.
+const MAX_TRACKED_ADDRESSES: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LimiterConfig {
+    /// Maximal number of handshakes in flight.
+    pub max_handshakes: usize,
+    /// Handshakes per second allowed for one IP address, IPv6 addresses
+    /// of one /64 network are counted together.
+    pub rate: f64,
+    /// Number of handshakes one IP address can start at once.
+    pub burst: u32,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        LimiterConfig {
+            max_handshakes: 256,
+            rate: 10.0,
+            burst: 20,
+        }
+    }
+}
+
+/// Counters of the rejected handshakes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RejectionStats {
+    /// Rejected because the global limit of handshakes in flight was reached.
+    pub too_many_handshakes: u64,
+    /// Rejected by the per-address rate limit.
+    pub rate_limited: u64,
+    /// Aborted because the handshake wasn't completed in time.
+    pub timed_out: u64,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, config: &LimiterConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.tokens = (self.tokens + elapsed * config.rate).min(f64::from(config.burst));
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    in_flight: usize,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    // Keys of the buckets in the order they were created.
+    bucket_order: VecDeque<IpAddr>,
+    stats: RejectionStats,
+}
+
+/// Global cap of the handshakes in flight along with per-address token buckets.
+///
+/// Limiter can be cloned and shared between connections.
+#[derive(Debug, Clone)]
+pub struct HandshakeLimiter {
+    config: LimiterConfig,
+    state: Arc<Mutex<LimiterState>>,
+}
+
+/// Slot of the handshake in flight, it is freed on drop.
+#[derive(Debug)]
+pub struct HandshakePermit {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl HandshakePermit {
+    /// Frees the slot of the handshake which wasn't completed in time.
+    pub fn expire(self) {
+        self.state.lock().unwrap().stats.timed_out += 1;
+    }
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().in_flight -= 1;
+    }
+}
+
+impl HandshakeLimiter {
+    pub fn new(config: LimiterConfig) -> Self {
+        HandshakeLimiter {
+            config,
+            state: Arc::new(Mutex::new(LimiterState::default())),
+        }
+    }
+
+    /// Takes a slot for the handshake with the peer, if limits allow.
+    pub fn acquire(&self, addr: IpAddr) -> Result<HandshakePermit, io::Error> {
+        self.acquire_at(addr, Instant::now())
+    }
+
+    pub fn stats(&self) -> RejectionStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    fn acquire_at(&self, addr: IpAddr, now: Instant) -> Result<HandshakePermit, io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= self.config.max_handshakes {
+            state.stats.too_many_handshakes += 1;
+            return Err(io::Error::new(io::ErrorKind::Other, "Too many handshakes in flight"));
+        }
+
+        let key = bucket_key(addr);
+        if !state.buckets.contains_key(&key) {
+            if state.buckets.len() >= MAX_TRACKED_ADDRESSES {
+                if let Some(oldest) = state.bucket_order.pop_front() {
+                    state.buckets.remove(&oldest);
+                }
+            }
+            state.bucket_order.push_back(key);
+            state.buckets.insert(
+                key,
+                TokenBucket {
+                    tokens: f64::from(self.config.burst),
+                    updated: now,
+                },
+            );
+        }
+
+        let allowed = {
+            let bucket = state.buckets.get_mut(&key).unwrap();
+            bucket.refill(&self.config, now);
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                true
+            } else {
+                false
+            }
+        };
+        if !allowed {
+            state.stats.rate_limited += 1;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Handshake rate limit exceeded for {}", addr),
+            ));
+        }
+
+        state.in_flight += 1;
+        Ok(HandshakePermit {
+            state: self.state.clone(),
+        })
+    }
+}
+
+/// Host usually gets the whole IPv6 /64 network, so its addresses share the bucket.
+fn bucket_key(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => addr,
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            for segment in &mut segments[4..] {
+                *segment = 0;
+            }
+            IpAddr::V6(Ipv6Addr::from(segments))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use limiter::{HandshakeLimiter, LimiterConfig, RejectionStats, MAX_TRACKED_ADDRESSES};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_max_handshakes() {
+        let limiter = HandshakeLimiter::new(LimiterConfig {
+            max_handshakes: 2,
+            rate: 1.0,
+            burst: 10,
+        });
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let first = limiter.acquire(addr).unwrap();
+        let _second = limiter.acquire(addr).unwrap();
+        assert!(limiter.acquire(addr).is_err());
+        assert_eq!(limiter.in_flight(), 2);
+
+        drop(first);
+        assert!(limiter.acquire(addr).is_ok());
+        assert_eq!(limiter.in_flight(), 1);
+        assert_eq!(
+            limiter.stats(),
+            RejectionStats {
+                too_many_handshakes: 1,
+                rate_limited: 0,
+                timed_out: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = HandshakeLimiter::new(LimiterConfig {
+            max_handshakes: 100,
+            rate: 2.0,
+            burst: 3,
+        });
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_addr: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.acquire_at(addr, now).is_ok());
+        }
+        assert!(limiter.acquire_at(addr, now).is_err());
+        // Other addresses have their own buckets.
+        assert!(limiter.acquire_at(other_addr, now).is_ok());
+
+        // Tokens are refilled with the configured rate.
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.acquire_at(addr, later).is_ok());
+        assert!(limiter.acquire_at(addr, later).is_err());
+        assert_eq!(limiter.stats().rate_limited, 2);
+    }
+
+    #[test]
+    fn test_ipv6_network_bucket() {
+        let limiter = HandshakeLimiter::new(LimiterConfig {
+            max_handshakes: 100,
+            rate: 1.0,
+            burst: 2,
+        });
+        let now = Instant::now();
+
+        assert!(limiter.acquire_at("2001:db8::1".parse().unwrap(), now).is_ok());
+        assert!(limiter.acquire_at("2001:db8::ffff:2".parse().unwrap(), now).is_ok());
+        assert!(limiter.acquire_at("2001:db8::3".parse().unwrap(), now).is_err());
+        assert!(limiter.acquire_at("2001:db8:0:1::1".parse().unwrap(), now).is_ok());
+    }
+
+    #[test]
+    fn test_tracked_addresses_cap() {
+        let limiter = HandshakeLimiter::new(LimiterConfig {
+            max_handshakes: 1,
+            rate: 1.0,
+            burst: 1,
+        });
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        drop(limiter.acquire_at(addr, now).unwrap());
+        assert!(limiter.acquire_at(addr, now).is_err());
+
+        for i in 0..MAX_TRACKED_ADDRESSES as u32 * 2 {
+            drop(limiter.acquire_at(IpAddr::V4(Ipv4Addr::from(0x0b00_0000 + i)), now));
+        }
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.buckets.len(), MAX_TRACKED_ADDRESSES);
+        assert_eq!(state.bucket_order.len(), MAX_TRACKED_ADDRESSES);
+        // The oldest bucket has been dropped.
+        assert!(!state.buckets.contains_key(&addr));
+    }
+
+    #[test]
+    fn test_expired_permit() {
+        let limiter = HandshakeLimiter::new(LimiterConfig::default());
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+
+        limiter.acquire(addr).unwrap().expire();
+        drop(limiter.acquire(addr).unwrap());
+        assert_eq!(limiter.in_flight(), 0);
+        assert_eq!(limiter.stats().timed_out, 1);
+    }
+}
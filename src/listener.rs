@@ -0,0 +1,232 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accept loop which yields connections with completed handshake.
+
+use futures::future::Either;
+use futures::stream::FuturesUnordered;
+use futures::{Async, Future, Poll, Stream};
+use noise_main::{NoiseConnection, NoiseHandshake};
+use tokio_core::net::{Incoming, TcpListener};
+use tokio_core::reactor::{Handle, Timeout};
+use wrapper::HandshakeParams;
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Default number of handshakes run at once.
+pub const DEFAULT_MAX_HANDSHAKES: usize = 64;
+/// Default time in seconds given to the peer to complete the handshake.
+pub const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+type PendingHandshake = Box<Future<Item = NoiseConnection, Error = (SocketAddr, io::Error)>>;
+
+/// Listener which runs handshakes with the incoming connections concurrently.
+///
+/// Yields established connections, failed handshakes are logged and dropped.
+/// When the limit of the concurrent handshakes is reached, new connections
+/// wait in the backlog of the socket. Handshakes which aren't completed within
+/// the timeout fail, so stalled peers can't hold the slots.
+pub struct NoiseListener {
+    incoming: Incoming,
+    local_addr: SocketAddr,
+    params: HandshakeParams,
+    handle: Handle,
+    max_handshakes: usize,
+    handshake_timeout: Duration,
+    handshakes: FuturesUnordered<PendingHandshake>,
+    incoming_finished: bool,
+}
+
+impl NoiseListener {
+    pub fn bind(addr: &SocketAddr, params: HandshakeParams, handle: &Handle) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr, handle)?;
+        Ok(NoiseListener {
+            local_addr: listener.local_addr()?,
+            incoming: listener.incoming(),
+            params,
+            handle: handle.clone(),
+            max_handshakes: DEFAULT_MAX_HANDSHAKES,
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS),
+            handshakes: FuturesUnordered::new(),
+            incoming_finished: false,
+        })
+    }
+
+    pub fn set_max_handshakes(&mut self, max_handshakes: usize) {
+        self.max_handshakes = max_handshakes;
+    }
+
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = handshake_timeout;
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Starts handshakes with the accepted connections while there is room for them.
+    fn accept(&mut self) -> Result<(), io::Error> {
+        while !self.incoming_finished && self.handshakes.len() < self.max_handshakes {
+            match self.incoming.poll()? {
+                Async::Ready(Some((stream, addr))) => {
+                    let timeout = Timeout::new(self.handshake_timeout, &self.handle)?;
+                    let handshake = NoiseHandshake::accept(&self.params, stream)
+                        .select2(timeout)
+                        .then(move |res| match res {
+                            Ok(Either::A((connection, _))) => Ok(connection),
+                            Ok(Either::B(_)) => Err((addr, io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))),
+                            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err((addr, e)),
+                        });
+                    self.handshakes.push(Box::new(handshake));
+                }
+                Async::Ready(None) => self.incoming_finished = true,
+                Async::NotReady => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Stream for NoiseListener {
+    type Item = NoiseConnection;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<NoiseConnection>, io::Error> {
+        loop {
+            self.accept()?;
+            match self.handshakes.poll() {
+                Ok(Async::Ready(Some(connection))) => return Ok(Async::Ready(Some(connection))),
+                Ok(Async::Ready(None)) if self.incoming_finished => return Ok(Async::Ready(None)),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // Room for one more handshake has been freed, so we try to accept again.
+                Err((addr, e)) => warn!("Handshake with {} failed: {}", addr, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{join_all, Either};
+    use futures::{Future, Stream};
+    use listener::NoiseListener;
+    use noise_main::NoiseHandshake;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::{Core, Timeout};
+    use tokio_io::io::write_all;
+    use wrapper::HandshakeParams;
+
+    #[test]
+    fn test_listener_several_peers() {
+        let addr: SocketAddr = "127.0.0.1:45601".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = NoiseListener::bind(&addr, HandshakeParams::new(1024), &handle).unwrap();
+
+        let clients_params: Vec<_> = (0..3).map(|_| HandshakeParams::new(1024)).collect();
+        let clients = clients_params
+            .iter()
+            .map(|params| {
+                let params = params.clone();
+                TcpStream::connect(&addr, &handle)
+                    .and_then(move |stream| NoiseHandshake::connect(&params, stream))
+                    .map(|connection| connection.framed)
+            })
+            .collect::<Vec<_>>();
+
+        let (connections, _clients) = core.run(listener.take(3).collect().join(join_all(clients))).unwrap();
+
+        let mut remote_keys: Vec<_> = connections.iter().map(|connection| connection.remote_key.clone()).collect();
+        let mut expected_keys: Vec<_> = clients_params.iter().map(|params| params.public_key.clone()).collect();
+        remote_keys.sort();
+        expected_keys.sort();
+        assert_eq!(remote_keys, expected_keys);
+        assert!(connections.iter().all(|connection| connection.peer_addr.ip() == addr.ip()));
+    }
+
+    #[test]
+    fn test_listener_skips_failed_handshake() {
+        let addr: SocketAddr = "127.0.0.1:45602".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = NoiseListener::bind(&addr, HandshakeParams::new(1024), &handle).unwrap();
+
+        let bad_client = TcpStream::connect(&addr, &handle).and_then(|stream| write_all(stream, vec![0xff; 64]));
+        core.run(bad_client).unwrap();
+
+        let params = HandshakeParams::new(1024);
+        let client_params = params.clone();
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(move |stream| NoiseHandshake::send(&client_params, stream));
+
+        let ((connection, _), _) = core.run(listener.into_future().map_err(|(e, _)| e).join(client))
+            .unwrap();
+        assert_eq!(connection.unwrap().remote_key, params.public_key);
+    }
+
+    #[test]
+    fn test_listener_max_handshakes() {
+        let addr: SocketAddr = "127.0.0.1:45603".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let mut listener = NoiseListener::bind(&addr, HandshakeParams::new(1024), &handle).unwrap();
+        listener.set_max_handshakes(1);
+
+        // Peer which never starts the handshake occupies the only slot.
+        let _stalled = core.run(TcpStream::connect(&addr, &handle)).unwrap();
+
+        let params = HandshakeParams::new(1024);
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(move |stream| NoiseHandshake::send(&params, stream));
+        handle.spawn(client.map(drop).map_err(drop));
+
+        let timeout = Timeout::new(Duration::from_millis(300), &handle).unwrap();
+        let res = core.run(listener.into_future().map_err(|(e, _)| e).select2(timeout));
+        match res {
+            Ok(Either::B(_)) => {}
+            _ => panic!("Handshake has been started beyond the limit"),
+        }
+    }
+
+    #[test]
+    fn test_listener_handshake_timeout() {
+        let addr: SocketAddr = "127.0.0.1:45604".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let mut listener = NoiseListener::bind(&addr, HandshakeParams::new(1024), &handle).unwrap();
+        listener.set_max_handshakes(1);
+        listener.set_handshake_timeout(Duration::from_millis(200));
+
+        // Stalled peer occupies the only slot until its handshake times out.
+        let _stalled = core.run(TcpStream::connect(&addr, &handle)).unwrap();
+
+        let params = HandshakeParams::new(1024);
+        let client_params = params.clone();
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(move |stream| NoiseHandshake::send(&client_params, stream));
+
+        let timeout = Timeout::new(Duration::from_secs(5), &handle).unwrap();
+        let accepted = listener.into_future().map_err(|(e, _)| e).join(client);
+        match core.run(accepted.select2(timeout)) {
+            Ok(Either::A((((connection, _), _), _))) => {
+                assert_eq!(connection.unwrap().remote_key, params.public_key);
+            }
+            _ => panic!("Slot of the stalled handshake hasn't been freed"),
+        }
+    }
+}
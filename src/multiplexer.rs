@@ -0,0 +1,832 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Multiplexing of logical substreams over one Noise transport connection.
+//!
+//! Every transport message carries one frame: 4 bytes stream id, 1 byte frame type
+//! and frame payload. Streams opened by the initiator have odd ids, streams opened
+//! by the responder have even ids. Stream ids aren't reused, so once they run out
+//! no more streams can be opened on the connection.
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::BytesMut;
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+pub const FRAME_HEADER_LENGTH: usize = 5;
+/// Maximal payload of one data frame, larger writes are split into several frames.
+pub const MAX_FRAME_PAYLOAD: usize = 16_384;
+/// Number of bytes the peer may send on a stream before it receives a window update.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+/// Default number of streams the peer may have open at once.
+pub const DEFAULT_MAX_STREAMS: usize = 256;
+/// Number of queued frames after which the driver stops reading from the transport,
+/// so the peer can't make the queue grow by frames which need an answer.
+const MAX_OUTGOING_FRAMES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameType {
+    Data = 0,
+    Open = 1,
+    Close = 2,
+    Reset = 3,
+    WindowUpdate = 4,
+}
+
+impl FrameType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(FrameType::Data),
+            1 => Some(FrameType::Open),
+            2 => Some(FrameType::Close),
+            3 => Some(FrameType::Reset),
+            4 => Some(FrameType::WindowUpdate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StreamState {
+    read_buf: BytesMut,
+    read_task: Option<Task>,
+    write_task: Option<Task>,
+    // Bytes we are allowed to send.
+    send_window: u32,
+    // Bytes the peer is allowed to send.
+    recv_window: u32,
+    // Bytes read by the application since the last window update.
+    recv_consumed: u32,
+    local_closed: bool,
+    remote_closed: bool,
+    reset: bool,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            read_buf: BytesMut::new(),
+            read_task: None,
+            write_task: None,
+            send_window: INITIAL_WINDOW,
+            recv_window: INITIAL_WINDOW,
+            recv_consumed: 0,
+            local_closed: false,
+            remote_closed: false,
+            reset: false,
+        }
+    }
+
+    fn notify(&mut self) {
+        if let Some(task) = self.read_task.take() {
+            task.notify();
+        }
+        if let Some(task) = self.write_task.take() {
+            task.notify();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    streams: HashMap<u32, StreamState>,
+    initiator: bool,
+    // `None` once stream ids are exhausted.
+    next_stream_id: Option<u32>,
+    // Streams opened by the peer beyond this limit are reset.
+    max_streams: usize,
+    incoming: VecDeque<u32>,
+    incoming_task: Option<Task>,
+    outgoing: VecDeque<BytesMut>,
+    // Streams which reset is queued, but hasn't been sent yet.
+    resetting: HashSet<u32>,
+    driver_task: Option<Task>,
+    closed: bool,
+}
+
+impl Shared {
+    fn new(initiator: bool) -> Self {
+        Shared {
+            streams: HashMap::new(),
+            initiator,
+            next_stream_id: Some(if initiator { 1 } else { 2 }),
+            max_streams: DEFAULT_MAX_STREAMS,
+            incoming: VecDeque::new(),
+            incoming_task: None,
+            outgoing: VecDeque::new(),
+            resetting: HashSet::new(),
+            driver_task: None,
+            closed: false,
+        }
+    }
+
+    /// Returns `true` if the stream with `id` has been opened by the peer.
+    fn is_remote(&self, id: u32) -> bool {
+        (id % 2 == 1) != self.initiator
+    }
+
+    fn send_frame(&mut self, id: u32, frame_type: FrameType, payload: &[u8]) {
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+        let mut header = [0u8; FRAME_HEADER_LENGTH];
+        LittleEndian::write_u32(&mut header[..4], id);
+        header[4] = frame_type as u8;
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(payload);
+
+        self.outgoing.push_back(frame);
+        if let Some(task) = self.driver_task.take() {
+            task.notify();
+        }
+    }
+
+    /// Queues reset of the stream, unless its reset is already queued.
+    fn send_reset(&mut self, id: u32) {
+        if self.resetting.insert(id) {
+            self.send_frame(id, FrameType::Reset, &[]);
+        }
+    }
+
+    /// Takes the next frame to be sent.
+    fn pop_outgoing(&mut self) -> Option<BytesMut> {
+        let frame = self.outgoing.pop_front()?;
+        if frame[4] == FrameType::Reset as u8 {
+            self.resetting.remove(&LittleEndian::read_u32(&frame[..4]));
+        }
+        Some(frame)
+    }
+
+    fn handle_frame(&mut self, mut frame: BytesMut) -> Result<(), io::Error> {
+        if frame.len() < FRAME_HEADER_LENGTH {
+            return Err(protocol_error("Multiplexer frame is too short"));
+        }
+
+        let header = frame.split_to(FRAME_HEADER_LENGTH);
+        let id = LittleEndian::read_u32(&header[..4]);
+        let frame_type = FrameType::from_u8(header[4])
+            .ok_or_else(|| protocol_error(format!("Unknown multiplexer frame type {}", header[4])))?;
+
+        if frame_type == FrameType::Open {
+            if id == 0 || !self.is_remote(id) {
+                return Err(protocol_error(format!("Stream id {} can't be opened by the peer", id)));
+            }
+            if self.streams.contains_key(&id) {
+                return Err(protocol_error(format!("Stream {} is already open", id)));
+            }
+            let remote_streams = self.streams.keys().filter(|&&id| self.is_remote(id)).count();
+            if remote_streams >= self.max_streams {
+                self.send_reset(id);
+                return Ok(());
+            }
+            self.streams.insert(id, StreamState::new());
+            self.incoming.push_back(id);
+            if let Some(task) = self.incoming_task.take() {
+                task.notify();
+            }
+            return Ok(());
+        }
+
+        if !self.streams.contains_key(&id) {
+            // Nobody is going to read data of the stream we have forgotten about,
+            // so the peer is asked to stop sending it.
+            if frame_type == FrameType::Data {
+                self.send_reset(id);
+            }
+            return Ok(());
+        }
+
+        let remove = {
+            let stream = self.streams.get_mut(&id).unwrap();
+
+            match frame_type {
+                FrameType::Data => {
+                    if frame.len() > stream.recv_window as usize {
+                        return Err(protocol_error(format!("Stream {} exceeded its window", id)));
+                    }
+                    stream.recv_window -= frame.len() as u32;
+                    stream.read_buf.extend_from_slice(&frame);
+                }
+                FrameType::Close => stream.remote_closed = true,
+                FrameType::Reset => stream.reset = true,
+                FrameType::WindowUpdate => {
+                    if frame.len() != 4 {
+                        return Err(protocol_error("Malformed window update"));
+                    }
+                    stream.send_window = stream.send_window.saturating_add(LittleEndian::read_u32(&frame));
+                }
+                FrameType::Open => unreachable!(),
+            }
+
+            stream.notify();
+            // Reset streams are kept until the handle is dropped, so it can observe the reset.
+            !stream.reset && stream.local_closed && stream.remote_closed && stream.read_buf.is_empty()
+        };
+
+        if remove {
+            self.streams.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        for stream in self.streams.values_mut() {
+            stream.notify();
+        }
+        if let Some(task) = self.incoming_task.take() {
+            task.notify();
+        }
+    }
+}
+
+fn protocol_error<S: AsRef<str>>(s: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, s.as_ref())
+}
+
+/// Handle used to open and accept substreams, can be cloned.
+#[derive(Debug, Clone)]
+pub struct Multiplexer {
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Multiplexer {
+    /// Creates multiplexer over the established Noise connection.
+    ///
+    /// Returned driver performs all I/O on the `transport` and should be spawned
+    /// on the event loop, substreams make no progress without it.
+    pub fn new<T>(transport: T, initiator: bool) -> (Multiplexer, MultiplexerDriver<T>)
+    where
+        T: Stream<Item = BytesMut, Error = io::Error> + Sink<SinkItem = BytesMut, SinkError = io::Error>,
+    {
+        let shared = Rc::new(RefCell::new(Shared::new(initiator)));
+
+        let driver = MultiplexerDriver {
+            transport,
+            shared: shared.clone(),
+            pending: None,
+        };
+        (Multiplexer { shared }, driver)
+    }
+
+    /// Sets the number of streams the peer may have open at once,
+    /// streams it opens beyond the limit are reset.
+    pub fn set_max_streams(&self, max_streams: usize) {
+        self.shared.borrow_mut().max_streams = max_streams;
+    }
+
+    /// Opens new substream, the remote side receives it from `incoming`.
+    /// Fails if the connection is closed or stream ids are exhausted.
+    pub fn open_stream(&self) -> Result<Substream, io::Error> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Connection is closed"));
+        }
+
+        let id = shared
+            .next_stream_id
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Stream ids are exhausted"))?;
+        shared.next_stream_id = id.checked_add(2);
+        shared.streams.insert(id, StreamState::new());
+        shared.send_frame(id, FrameType::Open, &[]);
+
+        Ok(Substream {
+            id,
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Returns stream of substreams opened by the remote side.
+    pub fn incoming(&self) -> Incoming {
+        Incoming {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Future which reads and writes frames of the underlying transport.
+/// Resolves when the remote side closes the connection.
+pub struct MultiplexerDriver<T> {
+    transport: T,
+    shared: Rc<RefCell<Shared>>,
+    pending: Option<BytesMut>,
+}
+
+impl<T> MultiplexerDriver<T>
+where
+    T: Stream<Item = BytesMut, Error = io::Error> + Sink<SinkItem = BytesMut, SinkError = io::Error>,
+{
+    fn poll_transport(&mut self) -> Poll<(), io::Error> {
+        self.shared.borrow_mut().driver_task = Some(task::current());
+
+        loop {
+            self.send_outgoing()?;
+            // Transport doesn't accept more frames, so reading is paused until it does.
+            if self.shared.borrow().outgoing.len() >= MAX_OUTGOING_FRAMES {
+                break;
+            }
+
+            match self.transport.poll()? {
+                Async::Ready(Some(frame)) => self.shared.borrow_mut().handle_frame(frame)?,
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break,
+            }
+        }
+
+        self.transport.poll_complete()?;
+        Ok(Async::NotReady)
+    }
+
+    fn send_outgoing(&mut self) -> Result<(), io::Error> {
+        loop {
+            let frame = match self.pending.take() {
+                Some(frame) => frame,
+                None => match self.shared.borrow_mut().pop_outgoing() {
+                    Some(frame) => frame,
+                    None => return Ok(()),
+                },
+            };
+
+            if let AsyncSink::NotReady(frame) = self.transport.start_send(frame)? {
+                self.pending = Some(frame);
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<T> Future for MultiplexerDriver<T>
+where
+    T: Stream<Item = BytesMut, Error = io::Error> + Sink<SinkItem = BytesMut, SinkError = io::Error>,
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let res = self.poll_transport();
+        match res {
+            Ok(Async::NotReady) => {}
+            _ => self.shared.borrow_mut().close(),
+        }
+        res
+    }
+}
+
+/// Stream of substreams opened by the remote side.
+#[derive(Debug)]
+pub struct Incoming {
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Stream for Incoming {
+    type Item = Substream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Substream>, io::Error> {
+        let mut shared = self.shared.borrow_mut();
+        match shared.incoming.pop_front() {
+            Some(id) => Ok(Async::Ready(Some(Substream {
+                id,
+                shared: self.shared.clone(),
+            }))),
+            None if shared.closed => Ok(Async::Ready(None)),
+            None => {
+                shared.incoming_task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Logical stream carried over the multiplexed connection.
+///
+/// Dropping substream closes it gracefully, use `reset` to abort it.
+#[derive(Debug)]
+pub struct Substream {
+    id: u32,
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Substream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Aborts the stream, discarding data which hasn't been read yet.
+    pub fn reset(self) {
+        let mut shared = self.shared.borrow_mut();
+        let send_reset = match shared.streams.get_mut(&self.id) {
+            Some(stream) => {
+                let send_reset = !stream.reset;
+                stream.reset = true;
+                send_reset
+            }
+            None => false,
+        };
+        if send_reset && !shared.closed {
+            shared.send_reset(self.id);
+        }
+        // The stream itself is forgotten when the handle is dropped.
+    }
+
+    fn close(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        let send_close = match shared.streams.get_mut(&self.id) {
+            Some(stream) => {
+                let send_close = !stream.local_closed && !stream.reset;
+                stream.local_closed = true;
+                send_close
+            }
+            None => false,
+        };
+        if send_close && !shared.closed {
+            shared.send_frame(self.id, FrameType::Close, &[]);
+        }
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "would block")
+}
+
+fn stream_reset() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "Stream was reset")
+}
+
+impl Read for Substream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut shared = self.shared.borrow_mut();
+        let closed = shared.closed;
+
+        let (len, window_update) = {
+            let stream = match shared.streams.get_mut(&self.id) {
+                Some(stream) => stream,
+                None => return Ok(0),
+            };
+
+            if stream.reset {
+                return Err(stream_reset());
+            }
+
+            if stream.read_buf.is_empty() {
+                if stream.remote_closed {
+                    return Ok(0);
+                }
+                if closed {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Connection is closed"));
+                }
+                stream.read_task = Some(task::current());
+                return Err(would_block());
+            }
+
+            let len = cmp::min(buf.len(), stream.read_buf.len());
+            buf[..len].copy_from_slice(&stream.read_buf.split_to(len));
+            stream.recv_consumed += len as u32;
+
+            // Return credit to the peer once half of the window is consumed.
+            if stream.recv_consumed >= INITIAL_WINDOW / 2 && !stream.remote_closed {
+                let consumed = stream.recv_consumed;
+                stream.recv_window += consumed;
+                stream.recv_consumed = 0;
+                (len, Some(consumed))
+            } else {
+                (len, None)
+            }
+        };
+
+        if let Some(consumed) = window_update {
+            if !closed {
+                let mut payload = [0u8; 4];
+                LittleEndian::write_u32(&mut payload, consumed);
+                shared.send_frame(self.id, FrameType::WindowUpdate, &payload);
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Substream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Connection is closed"));
+        }
+
+        let len = {
+            let stream = match shared.streams.get_mut(&self.id) {
+                Some(stream) => stream,
+                None => return Err(stream_reset()),
+            };
+
+            if stream.reset {
+                return Err(stream_reset());
+            }
+            if stream.local_closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Stream is closed"));
+            }
+            if stream.send_window == 0 {
+                stream.write_task = Some(task::current());
+                return Err(would_block());
+            }
+
+            let len = cmp::min(cmp::min(buf.len(), MAX_FRAME_PAYLOAD), stream.send_window as usize);
+            stream.send_window -= len as u32;
+            len
+        };
+
+        shared.send_frame(self.id, FrameType::Data, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Substream {}
+
+impl AsyncWrite for Substream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for Substream {
+    fn drop(&mut self) {
+        self.close();
+        self.shared.borrow_mut().streams.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use bytes::BytesMut;
+    use futures::future::poll_fn;
+    use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+    use multiplexer::{FrameType, Multiplexer, Shared, Substream, FRAME_HEADER_LENGTH, INITIAL_WINDOW, MAX_OUTGOING_FRAMES};
+    use noise_main::NoiseHandshake;
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_io::io::{read_to_end, shutdown, write_all};
+    use tokio_io::AsyncWrite;
+    use wrapper::HandshakeParams;
+
+    /// Multiplexer without the transport, its frames are delivered by `pump`.
+    fn multiplexer(initiator: bool) -> Multiplexer {
+        Multiplexer {
+            shared: Rc::new(RefCell::new(Shared::new(initiator))),
+        }
+    }
+
+    fn frame(id: u32, frame_type: FrameType, payload: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::from(vec![0u8; FRAME_HEADER_LENGTH]);
+        LittleEndian::write_u32(&mut frame[..4], id);
+        frame[4] = frame_type as u8;
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Delivers frames sent by `from` to `to`.
+    fn pump(from: &Multiplexer, to: &Multiplexer) -> Result<(), io::Error> {
+        loop {
+            let frame = from.shared.borrow_mut().outgoing.pop_front();
+            match frame {
+                Some(frame) => to.shared.borrow_mut().handle_frame(frame)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Transport of the peer which keeps sending data of unknown streams and never reads.
+    struct FloodingTransport {
+        next_id: u32,
+    }
+
+    impl Stream for FloodingTransport {
+        type Item = BytesMut;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+            self.next_id += 2;
+            Ok(Async::Ready(Some(frame(self.next_id, FrameType::Data, &[1]))))
+        }
+    }
+
+    impl Sink for FloodingTransport {
+        type SinkItem = BytesMut;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, frame: BytesMut) -> StartSend<BytesMut, io::Error> {
+            Ok(AsyncSink::NotReady(frame))
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn accept(multiplexer: &Multiplexer) -> Substream {
+        match multiplexer.incoming().poll().unwrap() {
+            Async::Ready(Some(substream)) => substream,
+            _ => panic!("No incoming stream"),
+        }
+    }
+
+    /// Sends `messages` over separate substreams and returns what the other side has received.
+    fn run_multiplexed(addr: &SocketAddr, messages: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, io::Error> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let params = HandshakeParams::new(1024);
+        let streams_count = messages.len() as u64;
+
+        let listener = TcpListener::bind(addr, &handle).unwrap();
+        let server_handle = handle.clone();
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| NoiseHandshake::listen(&params, stream.unwrap().0))
+            .and_then(move |framed| {
+                let (multiplexer, driver) = Multiplexer::new(framed, false);
+                server_handle.spawn(driver.map_err(|e| panic!("{}", e)));
+                multiplexer.incoming()
+                    .take(streams_count)
+                    .and_then(|substream| read_to_end(substream, Vec::new()).map(|(_, data)| data))
+                    .collect()
+            });
+
+        let client_handle = handle.clone();
+        let client = TcpStream::connect(addr, &handle)
+            .and_then(|stream| NoiseHandshake::send(&params, stream))
+            .and_then(move |framed| {
+                let (multiplexer, driver) = Multiplexer::new(framed, true);
+                client_handle.spawn(driver.map_err(|e| panic!("{}", e)));
+
+                let mut writes = Vec::new();
+                for message in messages {
+                    let substream = multiplexer.open_stream().unwrap();
+                    writes.push(write_all(substream, message).and_then(|(substream, _)| shutdown(substream)));
+                }
+                ::futures::future::join_all(writes)
+            });
+
+        core.run(server.join(client)).map(|(received, _)| received)
+    }
+
+    #[test]
+    fn test_multiplexer_several_streams() {
+        let messages = vec![b"consensus".to_vec(), b"gossip".to_vec(), b"rpc".to_vec()];
+        let received = run_multiplexed(&"127.0.0.1:45101".parse().unwrap(), messages.clone()).unwrap();
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn test_multiplexer_flow_control() {
+        // Message doesn't fit into the initial window, so the writer has to wait for window updates.
+        let message: Vec<u8> = (0..INITIAL_WINDOW as usize * 4).map(|i| i as u8).collect();
+        let received = run_multiplexed(&"127.0.0.1:45102".parse().unwrap(), vec![message.clone()]).unwrap();
+        assert_eq!(received, vec![message]);
+    }
+
+    #[test]
+    fn test_multiplexer_reset() {
+        let (client, server) = (multiplexer(true), multiplexer(false));
+        let mut client_stream = client.open_stream().unwrap();
+        client_stream.write_all(b"data").unwrap();
+        pump(&client, &server).unwrap();
+
+        let mut server_stream = accept(&server);
+        client_stream.reset();
+        pump(&client, &server).unwrap();
+
+        // Unread data is discarded and the stream can't be written anymore.
+        let mut buf = [0u8; 16];
+        assert_eq!(server_stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(server_stream.write(b"reply").unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_multiplexer_half_close() {
+        let (client, server) = (multiplexer(true), multiplexer(false));
+        let mut client_stream = client.open_stream().unwrap();
+        client_stream.write_all(b"ping").unwrap();
+        client_stream.shutdown().unwrap();
+        pump(&client, &server).unwrap();
+
+        let mut server_stream = accept(&server);
+        let mut buf = [0u8; 16];
+        assert_eq!(server_stream.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        assert_eq!(server_stream.read(&mut buf).unwrap(), 0);
+
+        // Server can still answer on the half-closed stream.
+        server_stream.write_all(b"pong").unwrap();
+        server_stream.shutdown().unwrap();
+        pump(&server, &client).unwrap();
+        assert!(client_stream.write(b"more").is_err());
+        assert_eq!(client_stream.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"pong");
+        assert_eq!(client_stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_multiplexer_window_exceeded() {
+        let server = multiplexer(false);
+        let mut shared = server.shared.borrow_mut();
+        shared.handle_frame(frame(1, FrameType::Open, &[])).unwrap();
+
+        let data = vec![0u8; INITIAL_WINDOW as usize];
+        shared.handle_frame(frame(1, FrameType::Data, &data)).unwrap();
+        assert!(shared.handle_frame(frame(1, FrameType::Data, &[0])).is_err());
+    }
+
+    #[test]
+    fn test_multiplexer_open_parity() {
+        let server = multiplexer(false);
+        let mut shared = server.shared.borrow_mut();
+        // Even ids belong to the responder itself, odd ids to the initiator.
+        assert!(shared.handle_frame(frame(0, FrameType::Open, &[])).is_err());
+        assert!(shared.handle_frame(frame(2, FrameType::Open, &[])).is_err());
+        shared.handle_frame(frame(1, FrameType::Open, &[])).unwrap();
+        assert!(shared.handle_frame(frame(1, FrameType::Open, &[])).is_err());
+        assert_eq!(shared.incoming, vec![1]);
+    }
+
+    #[test]
+    fn test_multiplexer_max_streams() {
+        let (client, server) = (multiplexer(true), multiplexer(false));
+        server.set_max_streams(2);
+        let streams: Vec<_> = (0..3).map(|_| client.open_stream().unwrap()).collect();
+        pump(&client, &server).unwrap();
+        pump(&server, &client).unwrap();
+
+        // The third stream is over the limit, so it is reset by the server.
+        assert_eq!(server.shared.borrow().incoming, vec![1, 3]);
+        assert!(!client.shared.borrow().streams[&streams[1].id()].reset);
+        assert!(client.shared.borrow().streams[&streams[2].id()].reset);
+
+        // Streams opened by the server itself aren't limited.
+        for _ in 0..3 {
+            server.open_stream().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_multiplexer_stream_ids_exhausted() {
+        let client = multiplexer(true);
+        client.shared.borrow_mut().next_stream_id = Some(u32::max_value() - 2);
+        assert_eq!(client.open_stream().unwrap().id(), u32::max_value() - 2);
+        assert_eq!(client.open_stream().unwrap().id(), u32::max_value());
+        assert!(client.open_stream().is_err());
+    }
+
+    #[test]
+    fn test_multiplexer_single_reset_per_stream() {
+        let server = multiplexer(false);
+        let mut shared = server.shared.borrow_mut();
+        shared.max_streams = 0;
+        shared.handle_frame(frame(1, FrameType::Open, &[])).unwrap();
+        for _ in 0..10 {
+            shared.handle_frame(frame(1, FrameType::Data, &[1])).unwrap();
+            shared.handle_frame(frame(3, FrameType::Data, &[1])).unwrap();
+        }
+        assert_eq!(shared.outgoing.len(), 2);
+
+        // Once the reset is sent, data of the stream is answered by a reset again.
+        shared.pop_outgoing().unwrap();
+        shared.handle_frame(frame(1, FrameType::Data, &[1])).unwrap();
+        assert_eq!(shared.outgoing.len(), 2);
+    }
+
+    #[test]
+    fn test_multiplexer_reading_paused_by_outgoing_queue() {
+        let (server, mut driver) = Multiplexer::new(FloodingTransport { next_id: 1 }, false);
+        let mut core = Core::new().unwrap();
+        core.run(poll_fn(|| -> Poll<(), ()> {
+            assert!(driver.poll().unwrap().is_not_ready());
+            Ok(Async::Ready(()))
+        })).unwrap();
+        assert_eq!(server.shared.borrow().outgoing.len(), MAX_OUTGOING_FRAMES);
+    }
+}
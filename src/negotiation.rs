@@ -0,0 +1,529 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Negotiation of the protocol version and Noise parameter set.
+//!
+//! The initiator prefixes its first handshake message with the offer, i.e. supported
+//! versions and parameter sets in the order of preference, followed by the selection
+//! its message has been made with. The responder deterministically selects the first
+//! offered version and parameter set which it supports. If its selection differs,
+//! it asks the initiator to repeat the first message with this selection.
+//!
+//! Both peers put the offer and the selection into the Noise prologue, so if they
+//! have been altered on the way, the handshake fails.
+
+use snow::params::{CipherChoice, DHChoice, HashChoice};
+use snow::CryptoResolver;
+use wrapper::NoiseError;
+
+/// Current version of the protocol.
+pub const PROTOCOL_VERSION: u8 = 1;
+const NEGOTIATION_LABEL: &[u8] = b"exonum noise negotiation";
+pub const SELECTION_LENGTH: usize = 2;
+/// Maximal number of versions or parameter sets in the offer, since it is prefixed with one-byte length.
+pub const MAX_OFFER_LENGTH: usize = 255;
+
+/// Diffie-Hellman function of the Noise protocol, defines the kind of the static keys.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Curve25519,
+    Curve448,
+}
+
+impl Curve {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Curve::Curve25519),
+            1 => Some(Curve::Curve448),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Curve::Curve25519 => 0,
+            Curve::Curve448 => 1,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Curve::Curve25519 => "25519",
+            Curve::Curve448 => "448",
+        }
+    }
+
+    /// Length of the public keys on the curve.
+    pub fn key_len(self) -> usize {
+        match self {
+            Curve::Curve25519 => 32,
+            Curve::Curve448 => 56,
+        }
+    }
+
+    pub fn choice(self) -> DHChoice {
+        match self {
+            Curve::Curve25519 => DHChoice::Curve25519,
+            Curve::Curve448 => DHChoice::Ed448,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "25519" => Some(Curve::Curve25519),
+            "448" => Some(Curve::Curve448),
+            _ => None,
+        }
+    }
+}
+
+/// Cipher function of the Noise protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cipher {
+    ChaChaPoly,
+    AesGcm,
+}
+
+impl Cipher {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Cipher::ChaChaPoly),
+            1 => Some(Cipher::AesGcm),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Cipher::ChaChaPoly => 0,
+            Cipher::AesGcm => 1,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::ChaChaPoly => "ChaChaPoly",
+            Cipher::AesGcm => "AESGCM",
+        }
+    }
+
+    pub fn choice(self) -> CipherChoice {
+        match self {
+            Cipher::ChaChaPoly => CipherChoice::ChaChaPoly,
+            Cipher::AesGcm => CipherChoice::AESGCM,
+        }
+    }
+}
+
+/// Hash function of the Noise protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hash {
+    Blake2s,
+    Sha256,
+    Sha512,
+    Blake2b,
+}
+
+impl Hash {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Hash::Blake2s),
+            1 => Some(Hash::Sha256),
+            2 => Some(Hash::Sha512),
+            3 => Some(Hash::Blake2b),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Hash::Blake2s => 0,
+            Hash::Sha256 => 1,
+            Hash::Sha512 => 2,
+            Hash::Blake2b => 3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Hash::Blake2s => "BLAKE2s",
+            Hash::Sha256 => "SHA256",
+            Hash::Sha512 => "SHA512",
+            Hash::Blake2b => "BLAKE2b",
+        }
+    }
+
+    pub fn choice(self) -> HashChoice {
+        match self {
+            Hash::Blake2s => HashChoice::Blake2s,
+            Hash::Sha256 => HashChoice::SHA256,
+            Hash::Sha512 => HashChoice::SHA512,
+            Hash::Blake2b => HashChoice::Blake2b,
+        }
+    }
+}
+
+pub const CHACHAPOLY_BLAKE2S: ParameterSet = ParameterSet {
+    curve: Curve::Curve25519,
+    cipher: Cipher::ChaChaPoly,
+    hash: Hash::Blake2s,
+};
+pub const CHACHAPOLY_SHA256: ParameterSet = ParameterSet {
+    curve: Curve::Curve25519,
+    cipher: Cipher::ChaChaPoly,
+    hash: Hash::Sha256,
+};
+pub const AESGCM_SHA256: ParameterSet = ParameterSet {
+    curve: Curve::Curve25519,
+    cipher: Cipher::AesGcm,
+    hash: Hash::Sha256,
+};
+
+/// Cipher suite of the Noise protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParameterSet {
+    pub curve: Curve,
+    pub cipher: Cipher,
+    pub hash: Hash,
+}
+
+impl Default for ParameterSet {
+    fn default() -> Self {
+        CHACHAPOLY_BLAKE2S
+    }
+}
+
+impl ParameterSet {
+    pub fn new(curve: Curve, cipher: Cipher, hash: Hash) -> Self {
+        ParameterSet { curve, cipher, hash }
+    }
+
+    // Curve is encoded in the two high bits of the byte, cipher in the next two
+    // and hash in the low half.
+    fn from_id(id: u8) -> Option<Self> {
+        Some(ParameterSet::new(
+            Curve::from_id(id >> 6)?,
+            Cipher::from_id((id >> 4) & 0x03)?,
+            Hash::from_id(id & 0x0f)?,
+        ))
+    }
+
+    fn id(self) -> u8 {
+        self.curve.id() << 6 | self.cipher.id() << 4 | self.hash.id()
+    }
+
+    /// Suffix of the Noise protocol name.
+    pub fn name(self) -> String {
+        format!("{}_{}_{}", self.curve.name(), self.cipher.name(), self.hash.name())
+    }
+
+    /// Checks that `resolver` implements the curve, cipher and hash of the set.
+    pub fn check_supported(self, resolver: &CryptoResolver) -> Result<(), NoiseError> {
+        if resolver.resolve_dh(&self.curve.choice()).is_none() {
+            return Err(NoiseError::new(format!(
+                "Curve {} isn't supported by the crypto resolver",
+                self.curve.name()
+            )));
+        }
+        if resolver.resolve_cipher(&self.cipher.choice()).is_none() {
+            return Err(NoiseError::new(format!(
+                "Cipher {} isn't supported by the crypto resolver",
+                self.cipher.name()
+            )));
+        }
+        if resolver.resolve_hash(&self.hash.choice()).is_none() {
+            return Err(NoiseError::new(format!(
+                "Hash {} isn't supported by the crypto resolver",
+                self.hash.name()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Serializes parameter sets, e.g. the ones supported by the responder.
+pub fn parameter_sets_to_bytes(parameter_sets: &[ParameterSet]) -> Vec<u8> {
+    parameter_sets.iter().map(|parameter_set| parameter_set.id()).collect()
+}
+
+/// Parses parameter sets, skipping the unknown ones.
+pub fn parameter_sets_from_bytes(bytes: &[u8]) -> Vec<ParameterSet> {
+    bytes.iter().filter_map(|&id| ParameterSet::from_id(id)).collect()
+}
+
+/// Protocol version and parameter set of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Selection {
+    pub version: u8,
+    pub parameter_set: ParameterSet,
+}
+
+impl Selection {
+    pub fn to_bytes(&self) -> [u8; SELECTION_LENGTH] {
+        [self.version, self.parameter_set.id()]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NoiseError> {
+        if bytes.len() != SELECTION_LENGTH {
+            return Err(NoiseError::new("Wrong length of the protocol selection"));
+        }
+        let parameter_set = ParameterSet::from_id(bytes[1])
+            .ok_or_else(|| NoiseError::new(format!("Unknown parameter set {}", bytes[1])))?;
+        Ok(Selection {
+            version: bytes[0],
+            parameter_set,
+        })
+    }
+}
+
+/// Protocol versions and parameter sets supported by the peer, the most preferred first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Negotiation {
+    pub versions: Vec<u8>,
+    pub parameter_sets: Vec<ParameterSet>,
+}
+
+impl Default for Negotiation {
+    fn default() -> Self {
+        Negotiation {
+            versions: vec![PROTOCOL_VERSION],
+            parameter_sets: vec![ParameterSet::default()],
+        }
+    }
+}
+
+impl Negotiation {
+    /// Selection the initiator starts the handshake with.
+    pub fn preferred(&self) -> Result<Selection, NoiseError> {
+        match (self.versions.first(), self.parameter_sets.first()) {
+            (Some(&version), Some(&parameter_set)) => Ok(Selection {
+                version,
+                parameter_set,
+            }),
+            _ => Err(NoiseError::new("No protocol versions or parameter sets are supported")),
+        }
+    }
+
+    /// Selects the first offered version and parameter set supported by us.
+    pub fn select(&self, offer: &Negotiation) -> Result<Selection, NoiseError> {
+        let version = offer
+            .versions
+            .iter()
+            .find(|version| self.versions.contains(version))
+            .ok_or_else(|| {
+                NoiseError::new(format!("No common protocol version, offered {:?}", offer.versions))
+            })?;
+        let parameter_set = self.common_parameter_set(offer).ok_or_else(|| {
+            NoiseError::new(format!(
+                "No common parameter set, offered {:?}",
+                offer.parameter_sets
+            ))
+        })?;
+        Ok(Selection {
+            version: *version,
+            parameter_set,
+        })
+    }
+
+    /// The first offered parameter set supported by us.
+    pub fn common_parameter_set(&self, offer: &Negotiation) -> Option<ParameterSet> {
+        offer
+            .parameter_sets
+            .iter()
+            .find(|parameter_set| self.parameter_sets.contains(parameter_set))
+            .cloned()
+    }
+
+    /// Checks the selection made by the responder from our offer.
+    pub fn check_selection(&self, bytes: &[u8]) -> Result<Selection, NoiseError> {
+        let selection = Selection::from_bytes(bytes)?;
+        if !self.versions.contains(&selection.version) || !self.parameter_sets.contains(&selection.parameter_set) {
+            return Err(NoiseError::new(format!(
+                "Responder has selected protocol which wasn't offered: {:?}",
+                selection
+            )));
+        }
+        Ok(selection)
+    }
+
+    /// Checks that the offer fits into the negotiation data.
+    pub fn check_lengths(&self) -> Result<(), NoiseError> {
+        if self.versions.len() > MAX_OFFER_LENGTH || self.parameter_sets.len() > MAX_OFFER_LENGTH {
+            return Err(NoiseError::new(format!(
+                "At most {} versions and parameter sets can be offered, got {} and {}",
+                MAX_OFFER_LENGTH,
+                self.versions.len(),
+                self.parameter_sets.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Serializes the offer along with the selection of the first handshake message,
+    /// fails if the offer is too long.
+    pub fn negotiation_data(&self, selection: &Selection) -> Result<Vec<u8>, NoiseError> {
+        self.check_lengths()?;
+        let mut data = Vec::with_capacity(2 + self.versions.len() + self.parameter_sets.len() + SELECTION_LENGTH);
+        data.push(self.versions.len() as u8);
+        data.extend_from_slice(&self.versions);
+        data.push(self.parameter_sets.len() as u8);
+        data.extend(parameter_sets_to_bytes(&self.parameter_sets));
+        data.extend_from_slice(&selection.to_bytes());
+        Ok(data)
+    }
+}
+
+/// Parses negotiation data at the start of the first handshake message, returns
+/// the offer, the selection of the initiator and length of the negotiation data.
+///
+/// Unknown parameter sets are skipped, since they can't be selected anyway.
+pub fn parse_negotiation_data(msg: &[u8]) -> Result<(Negotiation, Selection, usize), NoiseError> {
+    let truncated = || NoiseError::new("Truncated negotiation data");
+
+    let versions_len = *msg.first().ok_or_else(truncated)? as usize;
+    let versions = msg.get(1..1 + versions_len).ok_or_else(truncated)?.to_vec();
+    let mut pos = 1 + versions_len;
+
+    let sets_len = *msg.get(pos).ok_or_else(truncated)? as usize;
+    let parameter_sets = parameter_sets_from_bytes(msg.get(pos + 1..pos + 1 + sets_len).ok_or_else(truncated)?);
+    pos += 1 + sets_len;
+
+    let selection = Selection::from_bytes(msg.get(pos..pos + SELECTION_LENGTH).ok_or_else(truncated)?)?;
+    pos += SELECTION_LENGTH;
+
+    let offer = Negotiation {
+        versions,
+        parameter_sets,
+    };
+    Ok((offer, selection, pos))
+}
+
+/// Prologue which binds the handshake to the negotiation data.
+pub fn prologue(negotiation_data: &[u8]) -> Vec<u8> {
+    let mut prologue = NEGOTIATION_LABEL.to_vec();
+    prologue.extend_from_slice(negotiation_data);
+    prologue
+}
+
+#[cfg(test)]
+mod tests {
+    use negotiation::{parse_negotiation_data, prologue, Negotiation, ParameterSet, Selection};
+    use negotiation::{parameter_sets_from_bytes, parameter_sets_to_bytes, Cipher, Curve, Hash};
+    use negotiation::{AESGCM_SHA256, CHACHAPOLY_BLAKE2S, CHACHAPOLY_SHA256, MAX_OFFER_LENGTH};
+    use snow::params::NoiseParams;
+    use wrapper::{resolver, HandshakeParams, NoiseWrapper};
+
+    fn negotiation(versions: Vec<u8>, parameter_sets: Vec<ParameterSet>) -> Negotiation {
+        Negotiation {
+            versions,
+            parameter_sets,
+        }
+    }
+
+    #[test]
+    fn test_negotiation_data() {
+        let offer = negotiation(vec![3, 1], vec![AESGCM_SHA256, CHACHAPOLY_BLAKE2S]);
+        let selection = offer.preferred().unwrap();
+        let mut msg = offer.negotiation_data(&selection).unwrap();
+        let len = msg.len();
+        msg.extend_from_slice(b"handshake message");
+
+        assert_eq!(parse_negotiation_data(&msg).unwrap(), (offer, selection, len));
+        for truncated in 0..len {
+            assert!(parse_negotiation_data(&msg[..truncated]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_negotiation_data_too_long() {
+        let offer = negotiation(vec![1; MAX_OFFER_LENGTH], vec![CHACHAPOLY_BLAKE2S; MAX_OFFER_LENGTH]);
+        let selection = offer.preferred().unwrap();
+        assert!(offer.negotiation_data(&selection).is_ok());
+
+        let offer = negotiation(vec![1; MAX_OFFER_LENGTH + 1], vec![CHACHAPOLY_BLAKE2S]);
+        assert!(offer.negotiation_data(&selection).is_err());
+        let offer = negotiation(vec![1], vec![CHACHAPOLY_BLAKE2S; MAX_OFFER_LENGTH + 1]);
+        assert!(offer.negotiation_data(&selection).is_err());
+
+        let mut params = HandshakeParams::new(1024);
+        assert!(params.set_negotiation(negotiation(vec![1; MAX_OFFER_LENGTH + 1], vec![CHACHAPOLY_BLAKE2S])).is_err());
+        assert!(params.set_parameter_sets(vec![CHACHAPOLY_BLAKE2S; MAX_OFFER_LENGTH + 1]).is_err());
+        assert!(params.set_negotiation(negotiation(vec![2, 1], vec![CHACHAPOLY_BLAKE2S])).is_ok());
+    }
+
+    #[test]
+    fn test_parameter_set_ids() {
+        let resolver = resolver();
+        for &curve in &[Curve::Curve25519, Curve::Curve448] {
+            for &cipher in &[Cipher::ChaChaPoly, Cipher::AesGcm] {
+                for &hash in &[Hash::Blake2s, Hash::Sha256, Hash::Sha512, Hash::Blake2b] {
+                    let parameter_set = ParameterSet::new(curve, cipher, hash);
+                    assert_eq!(
+                        parameter_sets_from_bytes(&parameter_sets_to_bytes(&[parameter_set])),
+                        vec![parameter_set]
+                    );
+                    let name = format!("Noise_XX_{}", parameter_set.name());
+                    assert!(name.parse::<NoiseParams>().is_ok(), "{}", name);
+                    parameter_set.check_supported(&*resolver).unwrap();
+                }
+            }
+        }
+        assert_eq!(AESGCM_SHA256.name(), "25519_AESGCM_SHA256");
+        assert_eq!(parameter_sets_from_bytes(&[0x00, 0x20, 0x04, 0x52, 0x80]).len(), 2);
+    }
+
+    #[test]
+    fn test_select() {
+        let responder = negotiation(vec![1, 2], vec![CHACHAPOLY_BLAKE2S, CHACHAPOLY_SHA256]);
+
+        // Preference of the initiator wins.
+        let offer = negotiation(vec![3, 2, 1], vec![AESGCM_SHA256, CHACHAPOLY_SHA256]);
+        assert_eq!(
+            responder.select(&offer).unwrap(),
+            Selection {
+                version: 2,
+                parameter_set: CHACHAPOLY_SHA256,
+            }
+        );
+
+        assert!(responder.select(&negotiation(vec![3], vec![CHACHAPOLY_BLAKE2S])).is_err());
+        assert!(responder.select(&negotiation(vec![1], vec![AESGCM_SHA256])).is_err());
+        assert!(negotiation(vec![], vec![]).preferred().is_err());
+
+        // Initiator doesn't accept what it hasn't offered.
+        assert!(offer.check_selection(&[2, 1]).is_ok());
+        assert!(offer.check_selection(&[1, 0]).is_err());
+        assert!(offer.check_selection(&[2, 100]).is_err());
+    }
+
+    #[test]
+    fn test_tampered_offer_fails_handshake() {
+        let offer = negotiation(vec![2, 1], vec![CHACHAPOLY_BLAKE2S]);
+        let selection = offer.preferred().unwrap();
+        // Version 2 has been removed from the offer on the way, so the responder selects version 1.
+        let tampered = negotiation(vec![1], vec![CHACHAPOLY_BLAKE2S]);
+        let downgraded = tampered.preferred().unwrap();
+
+        let params = HandshakeParams::new(1024);
+        let initiator_params = params.with_selection(selection, prologue(&offer.negotiation_data(&selection).unwrap()));
+        let responder_params = params.with_selection(downgraded, prologue(&tampered.negotiation_data(&downgraded).unwrap()));
+
+        let mut initiator = NoiseWrapper::initiator(&initiator_params);
+        let mut responder = NoiseWrapper::responder(&responder_params);
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        responder.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, buf) = responder.write_handshake_msg().unwrap();
+        assert!(initiator.read_handshake_msg(&buf[..len]).is_err());
+    }
+}
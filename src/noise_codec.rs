@@ -1,14 +1,54 @@
-use byteorder::{BigEndian,LittleEndian, ByteOrder};
+use byteorder::{ByteOrder, LittleEndian};
 use bytes::BytesMut;
-use snow::Session;
+use futures::{Future, Sink};
+use std::error::Error as StdError;
+use std::fmt;
 use std::io;
-use tokio_io::codec::{Decoder, Encoder};
-use wrapper::NoiseWrapper;
+use tokio_io::codec::{Decoder, Encoder, Framed};
+use tokio_io::io::shutdown;
+use tokio_io::{AsyncRead, AsyncWrite};
+use wrapper::{NoiseError, NoiseWrapper, Payload};
 
+/// Types of the control frames.
+const PING_FRAME: u8 = 0;
+const PONG_FRAME: u8 = 1;
+const CLOSE_FRAME: u8 = 2;
+
+/// Reason code of the normal close.
+pub const CLOSE_NORMAL: u16 = 0;
+
+/// Error of the stream when the peer has closed connection with non-normal reason code.
+///
+/// Returned inside `io::Error` of `io::ErrorKind::ConnectionAborted` kind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerClosed {
+    pub reason: u16,
+}
+
+impl fmt::Display for PeerClosed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection closed by peer with reason {}", self.reason)
+    }
+}
+
+impl StdError for PeerClosed {
+    fn description(&self) -> &str {
+        "connection closed by peer"
+    }
+}
+
+/// Codec of transport messages.
+///
+/// Framing mode (plain or obfuscated with encrypted packet lengths) and padding
+/// are taken from `HandshakeParams::obfuscation` and `HandshakeParams::padding`.
+/// Control frames sent by the peer are skipped, except for the close frame.
+/// Stream ends after the close frame with normal reason, if the connection
+/// ends without it, `io::ErrorKind::UnexpectedEof` error is returned.
 #[allow(dead_code)]
 pub struct MessagesCodec {
     max_message_len: u32,
     session: NoiseWrapper,
+    closed: bool,
 }
 
 impl MessagesCodec {
@@ -16,63 +56,234 @@ impl MessagesCodec {
         MessagesCodec {
             max_message_len: 1024,
             session,
+            closed: false,
+        }
+    }
+
+    /// Converts codec into `FramesCodec`, which also exchanges control frames.
+    pub fn into_frames(self) -> FramesCodec {
+        FramesCodec {
+            session: self.session,
+            closed: self.closed,
         }
     }
 }
 
 impl Decoder for MessagesCodec {
-    type Item = String;
+    type Item = BytesMut;
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
-        if buf.len() < 2 {
-            return Ok(None);
-        };
+        while !self.closed {
+            match self.session.decrypt_payload(buf)? {
+                Some(Payload::Message(msg)) => return Ok(Some(msg)),
+                Some(Payload::Control(control)) => {
+                    if let Frame::Close(reason) = parse_control(&control)? {
+                        self.closed = true;
+                        check_close_reason(reason)?;
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+        check_after_close(buf)?;
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        match self.decode(buf)? {
+            Some(msg) => Ok(Some(msg)),
+            None => check_eof(self.closed).map(|_| None),
+        }
+    }
+}
+
+impl Encoder for MessagesCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        self.session.encrypt_msg(&msg, buf)?;
+        Ok(())
+    }
+}
 
-        let len = LittleEndian::read_u32(buf) as usize;
-        println!("message len {}", len);
+/// Frame of the transport: application message or control frame.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Message(BytesMut),
+    Ping,
+    Pong,
+    /// Close frame with the reason code, the last frame sent over the connection.
+    Close(u16),
+}
 
-        let data = buf.split_to(len + 4).to_vec();
-        let data = &data[4..];
-        let mut readed_data = vec![0u8; 0];
-        let mut readed_len = 0usize;
+/// Codec of transport frames, used by `Keepalive`.
+///
+/// Close frame with non-normal reason code is returned as `PeerClosed` error.
+pub struct FramesCodec {
+    session: NoiseWrapper,
+    closed: bool,
+}
 
-        data.chunks(65535).for_each(|chunk| {
-            let mut read_to = vec![0u8; chunk.len()];
-            println!("chunk len {:?}", chunk.len());
-//            readed_len += self.session.read(chunk, &mut read_to).unwrap();
-            readed_data.extend_from_slice(&read_to);
-        });
+impl Decoder for FramesCodec {
+    type Item = Frame;
+    type Error = io::Error;
 
-        let res = String::from_utf8_lossy(&readed_data[..readed_len]);
-        Ok(Some(res.to_string()))
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        if self.closed {
+            check_after_close(buf)?;
+            return Ok(None);
+        }
+
+        let frame = match self.session.decrypt_payload(buf)? {
+            Some(Payload::Message(msg)) => Frame::Message(msg),
+            Some(Payload::Control(control)) => parse_control(&control)?,
+            None => return Ok(None),
+        };
+        if let Frame::Close(reason) = frame {
+            self.closed = true;
+            check_close_reason(reason)?;
+        }
+        Ok(Some(frame))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None => check_eof(self.closed).map(|_| None),
+        }
     }
 }
 
-impl Encoder for MessagesCodec {
-    type Item = String;
+impl Encoder for FramesCodec {
+    type Item = Frame;
     type Error = io::Error;
 
-    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-        let mut len = 0usize;
-
-        let mut write_to_buf = vec![0u8; 0];
-
-        msg.as_bytes().chunks(65535 - 16).for_each(|chunk| {
-            let mut tmp_buf = vec![0u8; 65535];
-//            len += self.session
-//                .write_message( chunk,&mut tmp_buf)
-//                .unwrap();
-            println!("written_bytes {:?}", len);
-            write_to_buf.extend_from_slice(&tmp_buf);
-        });
-
-        println!("sending to socket len {}", len);
-        let mut msg_len_buf = vec![0u8; 4];
-        LittleEndian::write_u32(&mut msg_len_buf, len as u32);
-        let write_to_buf = &write_to_buf[0..len];
-        msg_len_buf.extend_from_slice(write_to_buf);
-        buf.extend_from_slice(&msg_len_buf);
+    fn encode(&mut self, frame: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        match frame {
+            Frame::Message(msg) => self.session.encrypt_msg(&msg, buf)?,
+            Frame::Ping => self.session.encrypt_control(&[PING_FRAME], buf)?,
+            Frame::Pong => self.session.encrypt_control(&[PONG_FRAME], buf)?,
+            Frame::Close(reason) => {
+                let mut control = [CLOSE_FRAME, 0, 0];
+                LittleEndian::write_u16(&mut control[1..], reason);
+                self.session.encrypt_control(&control, buf)?
+            }
+        };
         Ok(())
     }
 }
+
+/// Sends close frame with the given reason code and shuts down the connection.
+///
+/// Resolves to the underlying io object.
+pub fn close<T>(framed: Framed<T, MessagesCodec>, reason: u16) -> Box<Future<Item = T, Error = io::Error>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let (parts, codec) = framed.into_parts_and_codec();
+    let framed = Framed::from_parts(parts, codec.into_frames());
+    Box::new(
+        framed
+            .send(Frame::Close(reason))
+            .and_then(|framed| shutdown(framed.into_inner())),
+    )
+}
+
+fn parse_control(control: &[u8]) -> Result<Frame, io::Error> {
+    let frame = match (control.first().cloned(), control.len()) {
+        (Some(PING_FRAME), 1) => Frame::Ping,
+        (Some(PONG_FRAME), 1) => Frame::Pong,
+        (Some(CLOSE_FRAME), 3) => Frame::Close(LittleEndian::read_u16(&control[1..])),
+        _ => return Err(NoiseError::new(format!("Wrong control frame {:?}", control)).into()),
+    };
+    Ok(frame)
+}
+
+fn check_close_reason(reason: u16) -> Result<(), io::Error> {
+    if reason != CLOSE_NORMAL {
+        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, PeerClosed { reason }));
+    }
+    Ok(())
+}
+
+fn check_after_close(buf: &BytesMut) -> Result<(), io::Error> {
+    if !buf.is_empty() {
+        return Err(NoiseError::new("Data received after close frame").into());
+    }
+    Ok(())
+}
+
+/// Connection which ends without close frame might have been cut by an attacker.
+fn check_eof(closed: bool) -> Result<(), io::Error> {
+    if !closed {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection ended without close frame",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::{Future, Sink, Stream};
+    use noise_codec::{close, PeerClosed, CLOSE_NORMAL};
+    use noise_main::NoiseHandshake;
+    use std::io;
+    use std::net::SocketAddr;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_io::io::shutdown;
+    use wrapper::HandshakeParams;
+
+    /// Sends one message, then closes connection with `reason` or just shuts the socket down,
+    /// returns what the other side has received.
+    fn run_closing(addr: &SocketAddr, reason: Option<u16>) -> Result<Vec<BytesMut>, io::Error> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let params = HandshakeParams::new(1024);
+
+        let listener = TcpListener::bind(addr, &handle).unwrap();
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| NoiseHandshake::listen(&params, stream.unwrap().0))
+            .and_then(|framed| framed.collect());
+
+        let client = TcpStream::connect(addr, &handle)
+            .and_then(|stream| NoiseHandshake::send(&params, stream))
+            .and_then(|framed| framed.send(BytesMut::from("hello")))
+            .and_then(move |framed| -> Box<Future<Item = TcpStream, Error = io::Error>> {
+                match reason {
+                    Some(reason) => close(framed, reason),
+                    None => Box::new(shutdown(framed.into_inner())),
+                }
+            });
+        handle.spawn(client.map(drop).map_err(|e| panic!("{}", e)));
+
+        core.run(server)
+    }
+
+    #[test]
+    fn test_clean_close() {
+        let received = run_closing(&"127.0.0.1:45401".parse().unwrap(), Some(CLOSE_NORMAL)).unwrap();
+        assert_eq!(received, vec![BytesMut::from("hello")]);
+    }
+
+    #[test]
+    fn test_close_with_reason() {
+        let err = run_closing(&"127.0.0.1:45402".parse().unwrap(), Some(7)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        let closed = err.get_ref().and_then(|e| e.downcast_ref::<PeerClosed>());
+        assert_eq!(closed, Some(&PeerClosed { reason: 7 }));
+    }
+
+    #[test]
+    fn test_cut_connection() {
+        let err = run_closing(&"127.0.0.1:45403".parse().unwrap(), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
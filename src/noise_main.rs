@@ -13,78 +13,697 @@
 // limitations under the License.
 
 use byteorder::{ByteOrder, LittleEndian};
-use futures::future::{done, Future};
+use cookie::{CookieChecker, LoadGuard, COOKIE_LENGTH};
+use futures::future::{done, Either, Future};
+use kem::SharedKem;
+use keypair::KeyPair;
+use known_peers::PeerId;
+use negotiation::{parameter_sets_from_bytes, parameter_sets_to_bytes, parse_negotiation_data, prologue};
+use negotiation::{ParameterSet, Selection, SELECTION_LENGTH};
 use noise_codec::MessagesCodec;
+use std::error::Error as StdError;
+use std::fmt;
 use std::io;
+use std::net::SocketAddr;
 use tokio_core::net::TcpStream;
+use tokio_core::reactor::Timeout;
 use tokio_io::{AsyncRead, codec::Framed, io::{read_exact, write_all}};
 use wrapper::HANDSHAKE_HEADER_LENGTH;
+use wrapper::KEM_PSK_LOCATION;
 use wrapper::HandshakeParams;
 use wrapper::NoiseWrapper;
 
 pub type HandshakeResult = Box<Future<Item=Framed<TcpStream, MessagesCodec>, Error=io::Error>>;
+pub type ConnectionResult = Box<Future<Item=NoiseConnection, Error=io::Error>>;
+/// Stream and session, which has finished the handshake.
+type SessionResult = Box<Future<Item=(TcpStream, NoiseWrapper), Error=io::Error>>;
+/// Stream, tag and the first handshake message, along with the load guard of the cookie checker.
+type FirstMessageResult = Box<Future<Item=(TcpStream, u8, Vec<u8>, Option<LoadGuard>), Error=io::Error>>;
+type TaggedResult = Box<Future<Item=(TcpStream, u8, Vec<u8>), Error=io::Error>>;
+/// Stream, tag and the handshake message after negotiation, along with params bound to it.
+type NegotiationResult = Box<Future<Item=(TcpStream, u8, Vec<u8>, HandshakeParams), Error=io::Error>>;
+
+/// Network id and negotiation data of the initiator, which prefix its first handshake message.
+struct FirstMessage {
+    network_id: u32,
+    negotiation_data: Vec<u8>,
+    // Whether the responder has already rejected the previous selection.
+    retried: bool,
+}
+
+impl FirstMessage {
+    fn prefix(&self, msg: &[u8]) -> Vec<u8> {
+        let mut network_id = [0u8; NETWORK_ID_LENGTH];
+        LittleEndian::write_u32(&mut network_id, self.network_id);
+        [&network_id[..], &self.negotiation_data[..], msg].concat()
+    }
+}
+
+const NETWORK_ID_LENGTH: usize = 4;
+
+/// Peer belongs to the other network, returned inside `io::Error`
+/// of `InvalidData` kind by both peers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkMismatch {
+    pub local: u32,
+    pub remote: u32,
+}
+
+impl fmt::Display for NetworkMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Peer belongs to network {}, expected network {}", self.remote, self.local)
+    }
+}
+
+impl StdError for NetworkMismatch {
+    fn description(&self) -> &str {
+        "peer belongs to other network"
+    }
+}
+
+fn network_mismatch(local: u32, remote: &[u8]) -> io::Error {
+    if remote.len() != NETWORK_ID_LENGTH {
+        return other_error("Wrong length of the network id");
+    }
+    let remote = LittleEndian::read_u32(remote);
+    io::Error::new(io::ErrorKind::InvalidData, NetworkMismatch { local, remote })
+}
+
+/// Initiator and responder have no cipher suite in common, returned inside `io::Error`
+/// of `InvalidData` kind by both peers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CipherSuiteMismatch {
+    /// Parameter sets offered by the initiator.
+    pub offered: Vec<ParameterSet>,
+    /// Parameter sets supported by the responder.
+    pub supported: Vec<ParameterSet>,
+}
+
+impl fmt::Display for CipherSuiteMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = |parameter_sets: &[ParameterSet]| {
+            parameter_sets.iter().map(|parameter_set| parameter_set.name()).collect::<Vec<_>>().join(", ")
+        };
+        write!(
+            f,
+            "No common cipher suite, initiator offered [{}], responder supports [{}]",
+            names(&self.offered),
+            names(&self.supported)
+        )
+    }
+}
+
+impl StdError for CipherSuiteMismatch {
+    fn description(&self) -> &str {
+        "no common cipher suite"
+    }
+}
+
+/// Connection established by the handshake.
+pub struct NoiseConnection {
+    pub framed: Framed<TcpStream, MessagesCodec>,
+    pub peer_addr: SocketAddr,
+    /// Static key of the peer authenticated by the handshake.
+    pub remote_key: Vec<u8>,
+    /// Hash of the handshake, which binds upper layer authentication to the connection.
+    /// It is public, so it must not be used as a key.
+    pub handshake_hash: Vec<u8>,
+    exporter: KeyingMaterialExporter,
+}
+
+impl NoiseConnection {
+    /// Derives `len` bytes of secret keying material bound to the connection, see
+    /// `NoiseWrapper::export_keying_material`.
+    pub fn export_keying_material(&self, label: &[u8], len: usize) -> Result<Vec<u8>, io::Error> {
+        Ok(self.exporter.export(label, len)?)
+    }
+}
 
 #[derive(Debug)]
 pub struct NoiseHandshake {}
 
 impl NoiseHandshake {
     pub fn listen(params: &HandshakeParams, stream: TcpStream) -> HandshakeResult {
-        listen_handshake(stream, params)
+        Box::new(Self::accept(params, stream).map(|connection| connection.framed))
     }
 
     pub fn send(params: &HandshakeParams, stream: TcpStream) -> HandshakeResult {
-        send_handshake(stream, params)
+        Box::new(Self::connect(params, stream).map(|connection| connection.framed))
+    }
+
+    /// Same as `listen`, but also returns address and static key of the peer.
+    pub fn accept(params: &HandshakeParams, stream: TcpStream) -> ConnectionResult {
+        Box::new(listen_handshake(stream, params).and_then(into_connection))
+    }
+
+    /// Same as `send`, but also returns address and static key of the peer.
+    pub fn connect(params: &HandshakeParams, stream: TcpStream) -> ConnectionResult {
+        let params = params.clone();
+        let connection = send_handshake(stream, &params).and_then(move |(stream, noise)| {
+            remember_peer(&params, &stream, &noise);
+            into_connection((stream, noise))
+        });
+        Box::new(connection)
     }
 }
 
-fn listen_handshake(stream: TcpStream, params: &HandshakeParams) -> HandshakeResult {
-    let max_message_len = params.max_message_len;
-    let mut noise = NoiseWrapper::responder(params);
-    let framed = read(stream).and_then(move |(stream, msg)| {
-        read_handshake_msg(&msg, &mut noise)
-            .and_then(|_| {
-                write_handshake_msg(&mut noise)
-                    .and_then(|(len, buf)| write(stream, &buf, len))
-                    .and_then(|(stream, _msg)| read(stream))
-                    .and_then(move |(stream, msg)| {
-                        let _buf = noise.read_handshake_msg(&msg)?;
-                        let noise = noise.into_transport_mode()?;
-                        let framed = stream.framed(MessagesCodec::new(noise));
-                        Ok(framed)
-                    })
+fn into_connection((stream, noise): (TcpStream, NoiseWrapper)) -> Result<NoiseConnection, io::Error> {
+    let peer_addr = stream.peer_addr()?;
+    let remote_key = noise
+        .remote_static_key()
+        .ok_or_else(|| other_error("Static key of the peer hasn't been received"))?;
+    let noise = noise.into_transport_mode()?;
+    let handshake_hash = noise
+        .get_handshake_hash()
+        .expect("Handshake hash is stored in transport mode")
+        .to_vec();
+    let exporter = noise
+        .get_exporter()
+        .expect("Exporter is stored in transport mode")
+        .clone();
+    Ok(NoiseConnection {
+        framed: stream.framed(MessagesCodec::new(noise)),
+        peer_addr,
+        remote_key,
+        handshake_hash,
+        exporter,
+    })
+}
+
+/// Type of the handshake started by the initiator, sent before the first handshake message.
+const XX_HANDSHAKE: u8 = 0;
+const IK_HANDSHAKE: u8 = 1;
+const KEM_HANDSHAKE: u8 = 2;
+/// Set in the type of the handshake if the first message is prefixed with the cookie.
+const COOKIE_FLAG: u8 = 0x80;
+
+/// Responder's reply to the first handshake message, sent before the second handshake message.
+const HANDSHAKE_ACCEPTED: u8 = 0;
+const IK_FALLBACK: u8 = 1;
+/// Responder is under load, the initiator has to repeat the first message with the cookie.
+const COOKIE_REPLY: u8 = 2;
+/// Responder has selected other protocol version or parameter set, the initiator has to
+/// repeat the first message with this selection.
+const SELECTION_MISMATCH: u8 = 3;
+/// Initiator belongs to the other network, the reply carries network id of the responder.
+const NETWORK_MISMATCH: u8 = 4;
+/// Only one of the peers uses the KEM handshake.
+const KEM_MISMATCH: u8 = 5;
+/// Responder supports none of the offered parameter sets, the reply carries the supported ones.
+const SUITE_MISMATCH: u8 = 6;
+
+fn listen_handshake(stream: TcpStream, params: &HandshakeParams) -> SessionResult {
+    // Excess connections are rejected before anything is read from them.
+    let permit = match params.limiter {
+        Some(ref limiter) => match stream.peer_addr().and_then(|addr| limiter.acquire(addr.ip())) {
+            Ok(permit) => Some(permit),
+            Err(e) => return Box::new(done(Err(e))),
+        },
+        None => None,
+    };
+    let deadline = match params.handshake_timeout {
+        Some((timeout, ref handle)) => match Timeout::new(timeout, handle) {
+            Ok(deadline) => Some(deadline),
+            Err(e) => return Box::new(done(Err(e))),
+        },
+        None => None,
+    };
+
+    let params = params.clone();
+    let handshake = read_first_message(stream, params.cookies.clone())
+        .and_then(move |(stream, tag, msg, load_guard)| {
+            let handshake = negotiate(stream, &params, tag, msg, false).and_then(
+                |(stream, tag, msg, params)| -> SessionResult {
+                    match (tag, params.kem().cloned()) {
+                        (KEM_HANDSHAKE, Some(kem)) => listen_kem_handshake(stream, &params, &kem, &msg),
+                        (KEM_HANDSHAKE, None) => reject_kem(stream, "Initiator requires KEM handshake"),
+                        (XX_HANDSHAKE, Some(_)) | (IK_HANDSHAKE, Some(_)) => {
+                            reject_kem(stream, "Initiator doesn't support KEM handshake")
+                        }
+                        (XX_HANDSHAKE, None) => listen_xx_handshake(stream, &params, &msg),
+                        (IK_HANDSHAKE, None) => listen_ik_handshake(stream, &params, &msg),
+                        _ => Box::new(done(Err(other_error(format!("Unknown handshake type {}", tag))))),
+                    }
+                },
+            );
+            handshake.then(move |res| {
+                drop(load_guard);
+                res
             })
+        });
+
+    match deadline {
+        // Permit is released as soon as the deadline expires, the handshake is dropped along with the stream.
+        Some(deadline) => Box::new(handshake.select2(deadline).then(move |res| match res {
+            Ok(Either::A((session, _))) => Ok(session),
+            Ok(Either::B(_)) => {
+                if let Some(permit) = permit {
+                    permit.expire();
+                }
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Handshake deadline has expired"))
+            }
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+        })),
+        None => Box::new(handshake.then(move |res| {
+            drop(permit);
+            res
+        })),
+    }
+}
+
+/// Reads the first handshake message. If the responder is under load, the initiator
+/// is asked to repeat it with the cookie, so no session is created for unverified peers.
+fn read_first_message(stream: TcpStream, cookies: Option<CookieChecker>) -> FirstMessageResult {
+    let message = read_tagged(stream).and_then(move |(stream, tag, msg)| -> FirstMessageResult {
+        let cookies = match cookies {
+            Some(cookies) => cookies,
+            None => return Box::new(done(Ok((stream, tag, msg, None)))),
+        };
+        if tag & COOKIE_FLAG != 0 {
+            return Box::new(done(check_cookie(stream, &cookies, tag, msg)));
+        }
+        if !cookies.is_under_load() {
+            let load_guard = cookies.start_handshake();
+            return Box::new(done(Ok((stream, tag, msg, Some(load_guard)))));
+        }
+
+        let cookie = match stream.peer_addr() {
+            Ok(addr) => cookies.cookie(&addr),
+            Err(e) => return Box::new(done(Err(e))),
+        };
+        let message = write_tagged(stream, COOKIE_REPLY, &cookie, COOKIE_LENGTH)
+            .and_then(|(stream, _msg)| read_tagged(stream))
+            .and_then(move |(stream, tag, msg)| {
+                if tag & COOKIE_FLAG == 0 {
+                    return Err(other_error("Initiator hasn't repeated the handshake message with the cookie"));
+                }
+                check_cookie(stream, &cookies, tag, msg)
+            });
+        Box::new(message)
+    });
+
+    Box::new(message)
+}
+
+fn check_cookie(
+    stream: TcpStream,
+    cookies: &CookieChecker,
+    tag: u8,
+    mut msg: Vec<u8>,
+) -> Result<(TcpStream, u8, Vec<u8>, Option<LoadGuard>), io::Error> {
+    let addr = stream.peer_addr()?;
+    if msg.len() < COOKIE_LENGTH || !cookies.verify(&addr, &msg[..COOKIE_LENGTH]) {
+        return Err(other_error(format!("Invalid cookie from {}", addr)));
+    }
+    let msg = msg.split_off(COOKIE_LENGTH);
+    Ok((stream, tag & !COOKIE_FLAG, msg, Some(cookies.start_handshake())))
+}
+
+/// Checks network id and the negotiation data of the first handshake message. Initiator from
+/// the other network is rejected with `NetworkMismatch` error. If the initiator's selection
+/// differs from ours, it is asked to repeat the message once with our selection.
+///
+/// Returns the handshake message without the prefix and params bound to the negotiation.
+fn negotiate(stream: TcpStream, params: &HandshakeParams, tag: u8, mut msg: Vec<u8>, retried: bool) -> NegotiationResult {
+    if msg.len() < NETWORK_ID_LENGTH {
+        return Box::new(done(Err(other_error("Network id is missing in the handshake message"))));
+    }
+    let remote_network_id = LittleEndian::read_u32(&msg);
+    let msg = msg.split_off(NETWORK_ID_LENGTH);
+    if remote_network_id != params.network_id {
+        return reject_network(stream, params.network_id, remote_network_id);
+    }
+
+    let (offer, initiator_selection, len) = match parse_negotiation_data(&msg) {
+        Ok(parsed) => parsed,
+        Err(e) => return Box::new(done(Err(e.into()))),
+    };
+    if params.negotiation().common_parameter_set(&offer).is_none() {
+        return reject_suite(stream, offer.parameter_sets, params.negotiation().parameter_sets.clone());
+    }
+    let selection = match params.negotiation().select(&offer) {
+        Ok(selection) => selection,
+        Err(e) => return Box::new(done(Err(e.into()))),
+    };
+
+    if initiator_selection == selection {
+        let params = params.with_selection(selection, prologue(&msg[..len]));
+        return Box::new(done(Ok((stream, tag, msg[len..].to_vec(), params))));
+    }
+    if retried {
+        return Box::new(done(Err(other_error(format!(
+            "Initiator hasn't accepted selected protocol {:?}",
+            selection
+        )))));
+    }
+
+    let params = params.clone();
+    let negotiation = write_tagged(stream, SELECTION_MISMATCH, &selection.to_bytes(), SELECTION_LENGTH)
+        .and_then(|(stream, _msg)| read_tagged(stream))
+        .and_then(move |(stream, tag, msg)| negotiate(stream, &params, tag, msg, true));
+    Box::new(negotiation)
+}
+
+/// Tells the initiator our network id and fails the handshake.
+fn reject_network(stream: TcpStream, local: u32, remote: u32) -> NegotiationResult {
+    let mut network_id = [0u8; NETWORK_ID_LENGTH];
+    LittleEndian::write_u32(&mut network_id, local);
+    let rejection = write_tagged(stream, NETWORK_MISMATCH, &network_id, NETWORK_ID_LENGTH).and_then(move |_| {
+        let e = io::Error::new(io::ErrorKind::InvalidData, NetworkMismatch { local, remote });
+        Err::<(TcpStream, u8, Vec<u8>, HandshakeParams), _>(e)
     });
+    Box::new(rejection)
+}
+
+/// Tells the initiator the parameter sets we support and fails the handshake.
+fn reject_suite(stream: TcpStream, offered: Vec<ParameterSet>, supported: Vec<ParameterSet>) -> NegotiationResult {
+    let reply = parameter_sets_to_bytes(&supported);
+    let rejection = write_tagged(stream, SUITE_MISMATCH, &reply, reply.len()).and_then(move |_| {
+        let e = io::Error::new(io::ErrorKind::InvalidData, CipherSuiteMismatch { offered, supported });
+        Err::<(TcpStream, u8, Vec<u8>, HandshakeParams), _>(e)
+    });
+    Box::new(rejection)
+}
+
+/// Sends the first handshake message and reads the responder's reply,
+/// repeating the message with the cookie if the responder asks for it.
+fn send_first_message(stream: TcpStream, tag: u8, msg: Vec<u8>) -> TaggedResult {
+    let reply = write_tagged(stream, tag, &msg, msg.len())
+        .and_then(|(stream, _msg)| read_tagged(stream))
+        .and_then(move |(stream, reply_tag, reply)| -> TaggedResult {
+            if reply_tag != COOKIE_REPLY {
+                return Box::new(done(Ok((stream, reply_tag, reply))));
+            }
 
-    Box::new(framed)
+            let mut echo = reply;
+            echo.extend_from_slice(&msg);
+            Box::new(
+                write_tagged(stream, tag | COOKIE_FLAG, &echo, echo.len())
+                    .and_then(|(stream, _msg)| read_tagged(stream)),
+            )
+        });
+
+    Box::new(reply)
+}
+
+/// Error for the reply of the responder, which has rejected the handshake.
+fn rejection(params: &HandshakeParams, tag: u8, reply: &[u8]) -> io::Error {
+    match tag {
+        NETWORK_MISMATCH => network_mismatch(params.network_id, reply),
+        SUITE_MISMATCH => {
+            let mismatch = CipherSuiteMismatch {
+                offered: params.negotiation().parameter_sets.clone(),
+                supported: parameter_sets_from_bytes(reply),
+            };
+            io::Error::new(io::ErrorKind::InvalidData, mismatch)
+        }
+        KEM_MISMATCH if params.kem().is_some() => other_error("Responder doesn't support KEM handshake"),
+        KEM_MISMATCH => other_error("Responder requires KEM handshake"),
+        _ => other_error(format!("Unexpected handshake reply {}", tag)),
+    }
+}
+
+fn listen_xx_handshake(stream: TcpStream, params: &HandshakeParams, msg: &[u8]) -> SessionResult {
+    let mut noise = NoiseWrapper::responder(params);
+    let handshake = read_handshake_msg(msg, &mut noise)
+        .and_then(|_| {
+            write_handshake_msg(&mut noise)
+                .and_then(|(len, buf)| write_tagged(stream, HANDSHAKE_ACCEPTED, &buf, len))
+                .and_then(|(stream, _msg)| read(stream))
+                .and_then(move |(stream, msg)| {
+                    let _buf = noise.read_handshake_msg(&msg)?;
+                    Ok((stream, noise))
+                })
+        });
+
+    Box::new(handshake)
 }
 
-fn send_handshake(stream: TcpStream, params: &HandshakeParams) -> HandshakeResult {
-    let max_message_len = params.max_message_len;
-    let mut noise = NoiseWrapper::initiator(params);
-    let framed = write_handshake_msg(&mut noise)
-        .and_then(|(len, buf)| write(stream, &buf, len))
+/// Responder side of the KEM handshake: encapsulates the secret to the KEM key
+/// of the initiator and sends the ciphertext in the second message.
+fn listen_kem_handshake(stream: TcpStream, params: &HandshakeParams, kem: &SharedKem, msg: &[u8]) -> SessionResult {
+    let mut noise = NoiseWrapper::responder(params);
+    let response = match respond_kem(&mut noise, kem, msg) {
+        Ok(response) => response,
+        Err(e) => return Box::new(done(Err(e))),
+    };
+
+    let handshake = write_tagged(stream, HANDSHAKE_ACCEPTED, &response, response.len())
         .and_then(|(stream, _msg)| read(stream))
         .and_then(move |(stream, msg)| {
-            read_handshake_msg(&msg, &mut noise)
+            let _buf = noise.read_handshake_msg(&msg)?;
+            Ok((stream, noise))
+        });
+
+    Box::new(handshake)
+}
+
+fn respond_kem(noise: &mut NoiseWrapper, kem: &SharedKem, msg: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let (len, public_key) = noise.read_handshake_msg(msg)?;
+    let (ciphertext, secret) = kem.encapsulate(&public_key[..len])?;
+    noise.set_psk(KEM_PSK_LOCATION, &secret)?;
+    let (len, buf) = noise.write_handshake_msg_with_payload(&ciphertext)?;
+    Ok(buf[..len].to_vec())
+}
+
+fn reject_kem(stream: TcpStream, reason: &'static str) -> SessionResult {
+    let rejection = write_tagged(stream, KEM_MISMATCH, &[], 0)
+        .and_then(move |_| Err::<(TcpStream, NoiseWrapper), _>(other_error(reason)));
+    Box::new(rejection)
+}
+
+/// Responder side of Noise Pipes: completes `IK` handshake in one round trip
+/// or, if the initiator used stale static key, switches to the fallback handshake.
+fn listen_ik_handshake(stream: TcpStream, params: &HandshakeParams, msg: &[u8]) -> SessionResult {
+    let mut noise = NoiseWrapper::ik_responder(params);
+    if let Err(e) = noise.read_handshake_msg(msg) {
+        info!("Unable to read IK handshake message, falling back to XX: {}", e);
+        return listen_fallback_handshake(stream, params, msg);
+    }
+
+    let handshake = write_handshake_msg(&mut noise)
+        .and_then(|(len, buf)| write_tagged(stream, HANDSHAKE_ACCEPTED, &buf, len))
+        .map(move |(stream, _msg)| (stream, noise));
+
+    Box::new(handshake)
+}
+
+/// Responder side of `XXfallback`: answers with `<- e, ee, s, es` to the ephemeral key
+/// of the rejected `IK` message and reads `-> s, se` from the initiator.
+fn listen_fallback_handshake(stream: TcpStream, params: &HandshakeParams, ik_message: &[u8]) -> SessionResult {
+    let mut noise = match NoiseWrapper::fallback_responder(params, ik_message) {
+        Ok(noise) => noise,
+        Err(e) => return Box::new(done(Err(e.into()))),
+    };
+    let handshake = write_handshake_msg(&mut noise)
+        .and_then(|(len, buf)| write_tagged(stream, IK_FALLBACK, &buf, len))
+        .and_then(|(stream, _msg)| read(stream))
+        .and_then(move |(stream, msg)| read_handshake_msg(&msg, &mut noise).map(move |_| (stream, noise)));
+
+    Box::new(handshake)
+}
+
+fn send_handshake(stream: TcpStream, params: &HandshakeParams) -> SessionResult {
+    match params.negotiation().preferred() {
+        Ok(selection) => send_negotiated_handshake(stream, params, selection, false),
+        Err(e) => Box::new(done(Err(e.into()))),
+    }
+}
+
+fn send_negotiated_handshake(
+    stream: TcpStream,
+    params: &HandshakeParams,
+    selection: Selection,
+    retried: bool,
+) -> SessionResult {
+    let negotiation_data = match params.negotiation().negotiation_data(&selection) {
+        Ok(data) => data,
+        Err(e) => return Box::new(done(Err(e.into()))),
+    };
+    let params = params.with_selection(selection, prologue(&negotiation_data));
+    let remote_key = params.remote_key.clone().or_else(|| {
+        let known_peers = params.known_peers.as_ref()?;
+        let node_key = params
+            .remote_node_id
+            .as_ref()
+            .and_then(|node_id| known_peers.get(&PeerId::Node(node_id.clone())));
+        node_key.or_else(|| {
+            let addr = stream.peer_addr().ok()?;
+            known_peers.get(&PeerId::Address(addr))
+        })
+    });
+
+    let first_message = FirstMessage {
+        network_id: params.network_id,
+        negotiation_data,
+        retried,
+    };
+    match (params.kem().cloned(), remote_key) {
+        (Some(kem), _) => send_kem_handshake(stream, &params, kem, first_message),
+        (None, Some(ref remote_key)) if params.supports_ik() => {
+            send_ik_handshake(stream, &params, remote_key, first_message)
+        }
+        _ => send_xx_handshake(stream, &params, first_message),
+    }
+}
+
+/// Repeats the handshake with the selection of the responder.
+fn renegotiate(stream: TcpStream, params: &HandshakeParams, reply: &[u8], retried: bool) -> SessionResult {
+    if retried {
+        return Box::new(done(Err(other_error("Responder has rejected the protocol selection twice"))));
+    }
+    match params.negotiation().check_selection(reply) {
+        Ok(selection) => send_negotiated_handshake(stream, params, selection, true),
+        Err(e) => Box::new(done(Err(e.into()))),
+    }
+}
+
+/// Remembers static key of the responder, so the next connection can use `IK` pattern.
+///
+/// The connection is already authenticated, so failure to store the key is only logged.
+fn remember_peer(params: &HandshakeParams, stream: &TcpStream, noise: &NoiseWrapper) {
+    let (known_peers, remote_key) = match (params.known_peers.as_ref(), noise.remote_static_key()) {
+        (Some(known_peers), Some(remote_key)) => (known_peers, remote_key),
+        _ => return,
+    };
+
+    let mut peers = Vec::new();
+    if let Some(ref node_id) = params.remote_node_id {
+        peers.push(PeerId::Node(node_id.clone()));
+    }
+    if let Ok(addr) = stream.peer_addr() {
+        peers.push(PeerId::Address(addr));
+    }
+    for peer in peers {
+        if let Err(e) = known_peers.insert(peer.clone(), remote_key.clone()) {
+            warn!("Unable to remember static key of {}: {}", peer, e);
+        }
+    }
+}
+
+fn send_xx_handshake(stream: TcpStream, params: &HandshakeParams, first_message: FirstMessage) -> SessionResult {
+    let params = params.clone();
+    let mut noise = NoiseWrapper::initiator(&params);
+    let handshake = write_handshake_msg(&mut noise)
+        .and_then(move |(len, buf)| {
+            let msg = first_message.prefix(&buf[..len]);
+            send_first_message(stream, XX_HANDSHAKE, msg).map(move |(stream, tag, msg)| (stream, tag, msg, first_message))
+        })
+        .and_then(move |(stream, tag, msg, first_message)| -> SessionResult {
+            match tag {
+                HANDSHAKE_ACCEPTED => {}
+                SELECTION_MISMATCH => return renegotiate(stream, &params, &msg, first_message.retried),
+                _ => return Box::new(done(Err(rejection(&params, tag, &msg)))),
+            }
+            let handshake = read_handshake_msg(&msg, &mut noise)
                 .and_then(|_| {
                     write_handshake_msg(&mut noise)
                         .and_then(|(len, buf)| write(stream, &buf, len))
-                        .and_then(move |(stream, _msg)| {
-                            let noise = noise.into_transport_mode()?;
-                            let framed = stream.framed(MessagesCodec::new(noise));
-                            Ok(framed)
-                        })
-                })
+                        .map(move |(stream, _msg)| (stream, noise))
+                });
+            Box::new(handshake)
         });
 
-    Box::new(framed)
+    Box::new(handshake)
+}
+
+/// Initiator side of Noise Pipes: tries `IK` handshake with the known static key
+/// of the responder and transparently continues with the fallback handshake
+/// if the responder can't decrypt the first message.
+fn send_ik_handshake(
+    stream: TcpStream,
+    params: &HandshakeParams,
+    remote_key: &[u8],
+    first_message: FirstMessage,
+) -> SessionResult {
+    let params = params.clone();
+    // Ephemeral key is reused by `XXfallback`, if the responder asks for it.
+    let ephemeral = KeyPair::generate(params.curve());
+    let mut noise = NoiseWrapper::ik_initiator(&params, remote_key, &ephemeral.secret_key);
+    let handshake = write_handshake_msg(&mut noise)
+        .and_then(move |(len, buf)| {
+            let ik_message = buf[..len].to_vec();
+            send_first_message(stream, IK_HANDSHAKE, first_message.prefix(&ik_message))
+                .map(move |(stream, tag, msg)| (stream, tag, msg, ik_message, first_message.retried))
+        })
+        .and_then(move |(stream, tag, msg, ik_message, retried)| -> SessionResult {
+            match tag {
+                HANDSHAKE_ACCEPTED => Box::new(read_handshake_msg(&msg, &mut noise).map(move |_| (stream, noise))),
+                IK_FALLBACK => send_fallback_handshake(stream, &params, &ik_message, &ephemeral.secret_key, &msg),
+                SELECTION_MISMATCH => renegotiate(stream, &params, &msg, retried),
+                _ => Box::new(done(Err(rejection(&params, tag, &msg)))),
+            }
+        });
+
+    Box::new(handshake)
+}
+
+/// Initiator side of the KEM handshake: sends ephemeral KEM key in the first message
+/// and mixes the secret encapsulated by the responder into the keys as `psk3`.
+fn send_kem_handshake(
+    stream: TcpStream,
+    params: &HandshakeParams,
+    kem: SharedKem,
+    first_message: FirstMessage,
+) -> SessionResult {
+    let params = params.clone();
+    let (public_key, secret_key) = kem.generate_keypair();
+    let mut noise = NoiseWrapper::initiator(&params);
+    let handshake = done(noise.write_handshake_msg_with_payload(&public_key))
+        .map_err(io::Error::from)
+        .and_then(move |(len, buf)| {
+            let msg = first_message.prefix(&buf[..len]);
+            send_first_message(stream, KEM_HANDSHAKE, msg)
+                .map(move |(stream, tag, msg)| (stream, tag, msg, first_message.retried))
+        })
+        .and_then(move |(stream, tag, msg, retried)| -> SessionResult {
+            match tag {
+                HANDSHAKE_ACCEPTED => {}
+                SELECTION_MISMATCH => return renegotiate(stream, &params, &msg, retried),
+                _ => return Box::new(done(Err(rejection(&params, tag, &msg)))),
+            }
+            let finish = match finish_kem(&mut noise, &kem, &secret_key, &msg) {
+                Ok(finish) => finish,
+                Err(e) => return Box::new(done(Err(e))),
+            };
+            Box::new(write(stream, &finish, finish.len()).map(move |(stream, _msg)| (stream, noise)))
+        });
+
+    Box::new(handshake)
+}
+
+fn finish_kem(noise: &mut NoiseWrapper, kem: &SharedKem, secret_key: &[u8], msg: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let (len, ciphertext) = noise.read_handshake_msg(msg)?;
+    let secret = kem.decapsulate(secret_key, &ciphertext[..len])?;
+    noise.set_psk(KEM_PSK_LOCATION, &secret)?;
+    let (len, buf) = noise.write_handshake_msg()?;
+    Ok(buf[..len].to_vec())
+}
+
+/// Initiator side of `XXfallback`: reads the responder's answer to the ephemeral key
+/// of `ik_message` and finishes the handshake with its static key.
+fn send_fallback_handshake(
+    stream: TcpStream,
+    params: &HandshakeParams,
+    ik_message: &[u8],
+    ephemeral_key: &[u8],
+    msg: &[u8],
+) -> SessionResult {
+    let mut noise = NoiseWrapper::fallback_initiator(params, ik_message, ephemeral_key);
+    let handshake = read_handshake_msg(msg, &mut noise).and_then(|_| {
+        write_handshake_msg(&mut noise)
+            .and_then(|(len, buf)| write(stream, &buf, len))
+            .map(move |(stream, _msg)| (stream, noise))
+    });
+
+    Box::new(handshake)
 }
 
 pub fn read(sock: TcpStream) -> Box<Future<Item=(TcpStream, Vec<u8>), Error=io::Error>> {
     let buf = vec![0u8; HANDSHAKE_HEADER_LENGTH];
     Box::new(
         read_exact(sock, buf)
-            .and_then(|(stream, msg)| read_exact(stream, vec![0u8; msg[0] as usize])),
+            .and_then(|(stream, msg)| read_exact(stream, vec![0u8; LittleEndian::read_u16(&msg) as usize])),
     )
 }
 
@@ -99,6 +718,34 @@ pub fn write(
     Box::new(write_all(sock, message))
 }
 
+/// Reads handshake message prefixed with one byte tag.
+pub fn read_tagged(sock: TcpStream) -> Box<Future<Item=(TcpStream, u8, Vec<u8>), Error=io::Error>> {
+    Box::new(read(sock).and_then(|(stream, mut msg)| {
+        if msg.is_empty() {
+            return Err(other_error("Received empty handshake message"));
+        }
+        let tag = msg.remove(0);
+        Ok((stream, tag, msg))
+    }))
+}
+
+/// Writes handshake message prefixed with one byte tag.
+pub fn write_tagged(
+    sock: TcpStream,
+    tag: u8,
+    buf: &[u8],
+    len: usize,
+) -> Box<Future<Item=(TcpStream, Vec<u8>), Error=io::Error>> {
+    let mut message = Vec::with_capacity(len + 1);
+    message.push(tag);
+    message.extend_from_slice(&buf[0..len]);
+    write(sock, &message, len + 1)
+}
+
+fn other_error<S: AsRef<str>>(s: S) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, s.as_ref())
+}
+
 pub fn read_handshake_msg(
     input: &[u8],
     noise: &mut NoiseWrapper,
@@ -116,25 +763,43 @@ pub fn write_handshake_msg(
 
 mod tests {
     use byteorder::{ByteOrder, LittleEndian};
+    use bytes::BytesMut;
     use env_logger;
     use futures::{done, Future, Stream};
+    use cookie::CookieChecker;
+    use kem::{Kem, SharedKem, KEM_SECRET_LENGTH};
+    use keypair::KeyPair;
+    use known_peers::{KnownPeers, PeerId};
+    use limiter::{HandshakeLimiter, LimiterConfig};
+    use negotiation::{prologue, Cipher, Curve, Hash, Negotiation, ParameterSet};
+    use negotiation::{AESGCM_SHA256, CHACHAPOLY_BLAKE2S, CHACHAPOLY_SHA256};
     use noise_codec::MessagesCodec;
     use noise_main::HandshakeResult;
     use noise_main::NoiseHandshake;
+    use noise_main::CipherSuiteMismatch;
+    use noise_main::NetworkMismatch;
     use noise_main::read;
     use noise_main::read_handshake_msg;
+    use noise_main::read_tagged;
     use noise_main::write;
     use noise_main::write_handshake_msg;
+    use noise_main::write_tagged;
+    use noise_main::HANDSHAKE_ACCEPTED;
+    use noise_main::XX_HANDSHAKE;
     use snow::NoiseBuilder;
     use snow::params::NoiseParams;
     use snow::Session;
+    use sodium_wrapper::SodiumTestKem25519;
+    use std::env;
     use std::error::Error as StdError;
+    use std::fs;
     use std::io::{Read, Write};
     use std::io;
     use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
     use std::net::{SocketAddr, ToSocketAddrs};
     use std::sync::Arc;
     use std::thread;
+    use std::time::Duration;
     use tokio_core::net::{TcpListener, TcpStream};
     use tokio_core::reactor::Core;
     use tokio_io::AsyncRead;
@@ -143,11 +808,11 @@ mod tests {
     use wrapper::NoiseWrapper;
     use wrapper::NOISE_MIN_HANDSHAKE_MESSAGE_LENGTH;
     use wrapper::NOISE_MAX_MESSAGE_LENGTH;
+    use wrapper::PSK_LENGTH;
     use futures::Sink;
     use tokio;
     use std::sync::Mutex;
     use std::sync::mpsc::{self, Sender};
-    use std::time::Duration;
 
     #[derive(Debug, PartialEq, Copy, Clone)]
     pub enum HandshakeStep {
@@ -180,9 +845,7 @@ mod tests {
     fn test_noise_normal_handshake_remote() {
         env_logger::init();
 
-        let params = HandshakeParams {
-            max_message_len: 1024,
-        };
+        let params = HandshakeParams::new(1024);
 
         let mut core = Core::new().unwrap();
         let handle = core.handle();
@@ -236,6 +899,462 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_noise_pipes_ik_handshake() {
+        let responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        initiator_params.set_remote_key(responder_params.public_key.clone());
+
+        let res = run_pipes_handshake(&"127.0.0.1:45005".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_pipes_fallback_handshake() {
+        let responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        // Responder has changed its static key since the last connection.
+        initiator_params.set_remote_key(HandshakeParams::new(1024).public_key);
+
+        let res = run_pipes_handshake(&"127.0.0.1:45006".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_known_peers_reconnect() {
+        let addr: SocketAddr = "127.0.0.1:45007".parse().unwrap();
+        let responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        let known_peers = KnownPeers::new();
+        initiator_params.set_known_peers(known_peers.clone());
+
+        // First connection uses XX and remembers the responder.
+        let res = run_pipes_handshake(&addr, &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+        assert_eq!(known_peers.get(&PeerId::Address(addr)), Some(responder_params.public_key.clone()));
+
+        // Second connection uses IK with the remembered key.
+        let res = run_pipes_handshake(&addr, &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_known_peers_node_id() {
+        let addr: SocketAddr = "127.0.0.1:45036".parse().unwrap();
+        let responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        // Store can't be written, which doesn't fail the authenticated connection.
+        let known_peers = KnownPeers::open(env::temp_dir().join("noise_missing_dir/known_peers")).unwrap();
+        initiator_params.set_known_peers(known_peers.clone());
+        initiator_params.set_remote_node_id("node-1");
+
+        let res = run_pipes_handshake(&addr, &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+        let node = PeerId::Node("node-1".to_owned());
+        assert_eq!(known_peers.get(&node), Some(responder_params.public_key.clone()));
+
+        // Key of the node is found even if the node has moved to another address.
+        let _ = known_peers.remove(&PeerId::Address(addr));
+        let res = run_pipes_handshake(&addr, &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_psk_handshake() {
+        let mut responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        responder_params.add_psk(3, &[1; PSK_LENGTH]).unwrap();
+        initiator_params.add_psk(3, &[1; PSK_LENGTH]).unwrap();
+
+        let res = run_pipes_handshake(&"127.0.0.1:45008".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_psk_ik_handshake() {
+        let mut responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        responder_params.add_psk(2, &[1; PSK_LENGTH]).unwrap();
+        initiator_params.add_psk(2, &[1; PSK_LENGTH]).unwrap();
+        initiator_params.set_remote_key(responder_params.public_key.clone());
+
+        let res = run_pipes_handshake(&"127.0.0.1:45009".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_psk_mismatch() {
+        let mut responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        responder_params.add_psk(3, &[1; PSK_LENGTH]).unwrap();
+        initiator_params.add_psk(3, &[2; PSK_LENGTH]).unwrap();
+
+        let err = run_pipes_handshake(&"127.0.0.1:45010".parse().unwrap(), &initiator_params, &responder_params)
+            .unwrap_err();
+        assert!(err.to_string().contains("pre-shared keys don't match"));
+    }
+
+    #[test]
+    fn test_noise_wrong_psk_params() {
+        let mut params = HandshakeParams::new(1024);
+        assert!(params.add_psk(4, &[1; PSK_LENGTH]).is_err());
+        assert!(params.add_psk(0, &[1; PSK_LENGTH - 1]).is_err());
+        assert!(params.add_psk(0, &[1; PSK_LENGTH]).is_ok());
+        assert!(params.add_psk(0, &[1; PSK_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn test_noise_handshake_rate_limit() {
+        let limiter = HandshakeLimiter::new(LimiterConfig {
+            max_handshakes: 10,
+            rate: 0.001,
+            burst: 1,
+        });
+        let mut responder_params = HandshakeParams::new(1024);
+        responder_params.set_limiter(limiter.clone());
+        let initiator_params = HandshakeParams::new(1024);
+
+        let res = run_pipes_handshake(&"127.0.0.1:45011".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+        let res = run_pipes_handshake(&"127.0.0.1:45012".parse().unwrap(), &initiator_params, &responder_params);
+        assert!(res.is_err());
+
+        assert_eq!(limiter.stats().rate_limited, 1);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_noise_handshake_deadline() {
+        let addr: SocketAddr = "127.0.0.1:45035".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let limiter = HandshakeLimiter::new(LimiterConfig::default());
+        let mut responder_params = HandshakeParams::new(1024);
+        responder_params.set_limiter(limiter.clone());
+        responder_params.set_handshake_timeout(Duration::from_millis(200), &handle);
+
+        // Peer connects, but never sends the first message.
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| NoiseHandshake::listen(&responder_params, stream.unwrap().0));
+        let (res, _stalled) = core.run(server.then(Ok::<_, io::Error>).join(TcpStream::connect(&addr, &handle)))
+            .unwrap();
+
+        assert_eq!(res.err().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(limiter.stats().timed_out, 1);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_noise_cookie_handshake() {
+        let mut responder_params = HandshakeParams::new(1024);
+        // Cookies are required even without load.
+        responder_params.set_cookies(CookieChecker::new(0));
+        let initiator_params = HandshakeParams::new(1024);
+
+        let res = run_pipes_handshake(&"127.0.0.1:45013".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+
+        let mut initiator_params = HandshakeParams::new(1024);
+        initiator_params.set_remote_key(responder_params.public_key.clone());
+        let res = run_pipes_handshake(&"127.0.0.1:45014".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_negotiation() {
+        let mut responder_params = HandshakeParams::new(1024);
+        responder_params
+            .set_negotiation(Negotiation {
+                versions: vec![1, 2],
+                parameter_sets: vec![CHACHAPOLY_BLAKE2S, CHACHAPOLY_SHA256],
+            })
+            .unwrap();
+
+        // Responder doesn't support the preferred version and parameter set of the initiator,
+        // so the first message is repeated.
+        let mut initiator_params = HandshakeParams::new(1024);
+        initiator_params
+            .set_negotiation(Negotiation {
+                versions: vec![3, 2],
+                parameter_sets: vec![AESGCM_SHA256, CHACHAPOLY_SHA256],
+            })
+            .unwrap();
+        let res = run_pipes_handshake(&"127.0.0.1:45016".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+
+        initiator_params.set_remote_key(responder_params.public_key.clone());
+        let res = run_pipes_handshake(&"127.0.0.1:45017".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+
+        initiator_params.set_parameter_sets(vec![AESGCM_SHA256]).unwrap();
+        let res = run_pipes_handshake(&"127.0.0.1:45018".parse().unwrap(), &initiator_params, &responder_params);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_noise_prologue() {
+        let mut responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        responder_params.set_prologue(b"app v1");
+        initiator_params.set_prologue(b"app v1");
+
+        let res = run_pipes_handshake(&"127.0.0.1:45019".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+
+        initiator_params.set_prologue(b"app v2");
+        let res = run_pipes_handshake(&"127.0.0.1:45020".parse().unwrap(), &initiator_params, &responder_params);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_noise_cipher_suites() {
+        let mut params = HandshakeParams::new(1024);
+        params.set_cipher_suite(Cipher::AesGcm, Hash::Sha512).unwrap();
+        let res = run_pipes_handshake(&"127.0.0.1:45027".parse().unwrap(), &params, &params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+
+        params.set_cipher_suite(Cipher::ChaChaPoly, Hash::Blake2b).unwrap();
+        let res = run_pipes_handshake(&"127.0.0.1:45028".parse().unwrap(), &params, &params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_cipher_suite_mismatch() {
+        let addr: SocketAddr = "127.0.0.1:45029".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let mut responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        responder_params.set_cipher_suite(Cipher::AesGcm, Hash::Sha256).unwrap();
+        initiator_params.set_cipher_suite(Cipher::ChaChaPoly, Hash::Sha512).unwrap();
+
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(stream, _)| NoiseHandshake::listen(&responder_params, stream.unwrap().0))
+            .then(|res| Ok::<_, ()>(res.err().unwrap()));
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(move |stream| NoiseHandshake::send(&initiator_params, stream))
+            .then(|res| Ok::<_, ()>(res.err().unwrap()));
+
+        let (server_err, client_err) = core.run(server.join(client)).unwrap();
+        let expected = CipherSuiteMismatch {
+            offered: vec![ParameterSet::new(Curve::Curve25519, Cipher::ChaChaPoly, Hash::Sha512)],
+            supported: vec![AESGCM_SHA256],
+        };
+        let mismatch = |e: &io::Error| e.get_ref().and_then(|e| e.downcast_ref::<CipherSuiteMismatch>()).cloned();
+        assert_eq!(mismatch(&server_err), Some(expected.clone()));
+        assert_eq!(mismatch(&client_err), Some(expected));
+        assert_eq!(
+            client_err.to_string(),
+            "No common cipher suite, initiator offered [25519_ChaChaPoly_SHA512], \
+             responder supports [25519_AESGCM_SHA256]"
+        );
+    }
+
+    #[test]
+    fn test_noise_curve448() {
+        let responder_params = HandshakeParams::with_curve(Curve::Curve448, 1024);
+        let mut initiator_params = HandshakeParams::with_curve(Curve::Curve448, 1024);
+        let res = run_pipes_handshake(&"127.0.0.1:45030".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+
+        // Noise Pipes with 448 keys of the responder.
+        initiator_params.set_remote_key(responder_params.public_key.clone());
+        let res = run_pipes_handshake(&"127.0.0.1:45031".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+
+        let mut params = HandshakeParams::with_curve(Curve::Curve448, 1024);
+        params.set_cipher_suite(Cipher::AesGcm, Hash::Sha512).unwrap();
+        let res = run_pipes_handshake(&"127.0.0.1:45032".parse().unwrap(), &params, &params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_curve448_key_file() {
+        let path = env::temp_dir().join("noise_test_curve448_key_file");
+        KeyPair::generate(Curve::Curve448).save(&path).unwrap();
+        let responder_params = HandshakeParams::with_keypair(KeyPair::load(&path).unwrap(), 1024);
+        fs::remove_file(&path).unwrap();
+
+        let initiator_params = HandshakeParams::with_curve(Curve::Curve448, 1024);
+        let res = run_pipes_handshake(&"127.0.0.1:45033".parse().unwrap(), &initiator_params, &responder_params);
+        assert_eq!(&res.unwrap()[..], b"ping");
+    }
+
+    #[test]
+    fn test_noise_curve_mismatch() {
+        let addr: SocketAddr = "127.0.0.1:45034".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let responder_params = HandshakeParams::with_curve(Curve::Curve448, 1024);
+        let initiator_params = HandshakeParams::new(1024);
+
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(stream, _)| NoiseHandshake::listen(&responder_params, stream.unwrap().0))
+            .then(|res| Ok::<_, ()>(res.err().unwrap()));
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(move |stream| NoiseHandshake::send(&initiator_params, stream))
+            .then(|res| Ok::<_, ()>(res.err().unwrap()));
+
+        let (_, client_err) = core.run(server.join(client)).unwrap();
+        assert_eq!(
+            client_err.to_string(),
+            "No common cipher suite, initiator offered [25519_ChaChaPoly_BLAKE2s], \
+             responder supports [448_ChaChaPoly_BLAKE2s]"
+        );
+    }
+
+    #[test]
+    fn test_noise_network_mismatch() {
+        let addr: SocketAddr = "127.0.0.1:45021".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let mut responder_params = HandshakeParams::new(1024);
+        let mut initiator_params = HandshakeParams::new(1024);
+        responder_params.set_network_id(1);
+        initiator_params.set_network_id(2);
+
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(stream, _)| NoiseHandshake::listen(&responder_params, stream.unwrap().0))
+            .then(|res| Ok::<_, ()>(res.err().unwrap()));
+        let client = TcpStream::connect(&addr, &handle)
+            .and_then(move |stream| NoiseHandshake::send(&initiator_params, stream))
+            .then(|res| Ok::<_, ()>(res.err().unwrap()));
+
+        let (server_err, client_err) = core.run(server.join(client)).unwrap();
+        let mismatch = |e: &io::Error| e.get_ref().and_then(|e| e.downcast_ref::<NetworkMismatch>()).cloned();
+        assert_eq!(mismatch(&server_err), Some(NetworkMismatch { local: 1, remote: 2 }));
+        assert_eq!(mismatch(&client_err), Some(NetworkMismatch { local: 2, remote: 1 }));
+        assert_eq!(server_err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// KEM with key and ciphertext sizes of Kyber768, so handshake messages exceed 255 bytes.
+    struct PaddedKem;
+
+    const PADDED_PUBLIC_KEY_LENGTH: usize = 1184;
+    const PADDED_CIPHERTEXT_LENGTH: usize = 1088;
+
+    impl Kem for PaddedKem {
+        fn name(&self) -> &'static str {
+            "Padded25519"
+        }
+
+        fn generate_keypair(&self) -> (Vec<u8>, Vec<u8>) {
+            let (mut public_key, secret_key) = SodiumTestKem25519.generate_keypair();
+            public_key.resize(PADDED_PUBLIC_KEY_LENGTH, 0);
+            (public_key, secret_key)
+        }
+
+        fn encapsulate(&self, public_key: &[u8]) -> Result<(Vec<u8>, [u8; KEM_SECRET_LENGTH]), NoiseError> {
+            let (mut ciphertext, secret) = SodiumTestKem25519.encapsulate(&public_key[..32])?;
+            ciphertext.resize(PADDED_CIPHERTEXT_LENGTH, 0);
+            Ok((ciphertext, secret))
+        }
+
+        fn decapsulate(&self, secret_key: &[u8], ciphertext: &[u8]) -> Result<[u8; KEM_SECRET_LENGTH], NoiseError> {
+            SodiumTestKem25519.decapsulate(secret_key, &ciphertext[..32])
+        }
+    }
+
+    fn kem_params<K: Kem + 'static>(kem: K) -> HandshakeParams {
+        let mut params = HandshakeParams::new(1024);
+        params.set_kem(SharedKem::new(kem)).unwrap();
+        params
+    }
+
+    #[test]
+    fn test_noise_kem_handshake() {
+        let params = kem_params(SodiumTestKem25519);
+        let res = run_pipes_handshake(&"127.0.0.1:45022".parse().unwrap(), &params, &params);
+        assert_eq!(res.unwrap(), BytesMut::from("ping"));
+
+        let params = kem_params(PaddedKem);
+        let res = run_pipes_handshake(&"127.0.0.1:45023".parse().unwrap(), &params, &params);
+        assert_eq!(res.unwrap(), BytesMut::from("ping"));
+    }
+
+    #[test]
+    fn test_noise_kem_mismatch() {
+        let with_kem = kem_params(SodiumTestKem25519);
+        let plain = HandshakeParams::new(1024);
+        assert!(run_pipes_handshake(&"127.0.0.1:45024".parse().unwrap(), &with_kem, &plain).is_err());
+        assert!(run_pipes_handshake(&"127.0.0.1:45025".parse().unwrap(), &plain, &with_kem).is_err());
+    }
+
+    #[test]
+    fn test_noise_kem_secret_mismatch() {
+        // Responder encapsulates the secret to the other key, so psk3 differs.
+        struct WrongKem;
+
+        impl Kem for WrongKem {
+            fn name(&self) -> &'static str {
+                "Wrong25519"
+            }
+
+            fn generate_keypair(&self) -> (Vec<u8>, Vec<u8>) {
+                SodiumTestKem25519.generate_keypair()
+            }
+
+            fn encapsulate(&self, _public_key: &[u8]) -> Result<(Vec<u8>, [u8; KEM_SECRET_LENGTH]), NoiseError> {
+                let (public_key, _) = SodiumTestKem25519.generate_keypair();
+                SodiumTestKem25519.encapsulate(&public_key)
+            }
+
+            fn decapsulate(&self, secret_key: &[u8], ciphertext: &[u8]) -> Result<[u8; KEM_SECRET_LENGTH], NoiseError> {
+                SodiumTestKem25519.decapsulate(secret_key, ciphertext)
+            }
+        }
+
+        let initiator_params = kem_params(SodiumTestKem25519);
+        let responder_params = kem_params(WrongKem);
+        let res = run_pipes_handshake(&"127.0.0.1:45026".parse().unwrap(), &initiator_params, &responder_params);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_noise_connection_channel_binding() {
+        let addr: SocketAddr = "127.0.0.1:45015".parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let responder_params = HandshakeParams::new(1024);
+        let initiator_params = HandshakeParams::new(1024);
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let accept = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(stream, _)| NoiseHandshake::accept(&responder_params, stream.unwrap().0));
+        let connect = TcpStream::connect(&addr, &handle)
+            .and_then(move |stream| NoiseHandshake::connect(&initiator_params, stream));
+
+        let (responder, initiator) = core.run(accept.join(connect)).unwrap();
+        assert_eq!(responder.handshake_hash, initiator.handshake_hash);
+        assert_eq!(
+            responder.export_keying_material(b"rpc auth", 32).unwrap(),
+            initiator.export_keying_material(b"rpc auth", 32).unwrap()
+        );
+    }
+
     #[test]
     #[ignore]
     fn test_noise_bad_listen() {
@@ -274,9 +1393,7 @@ mod tests {
     fn run_handshake_listener(addr: &SocketAddr, step: HandshakeStep, sender: Sender<()>) -> Result<(), io::Error> {
         let mut core = Core::new().unwrap();
         let handle = core.handle();
-        let params = HandshakeParams {
-            max_message_len: 1024,
-        };
+        let params = HandshakeParams::new(1024);
 
         let fut_stream = TcpListener::bind(addr, &handle).unwrap();
         let fut = fut_stream.incoming()
@@ -303,13 +1420,35 @@ mod tests {
         core.run(fut)
     }
 
+    /// Runs handshake over loopback TCP and returns message sent by the initiator.
+    fn run_pipes_handshake(
+        addr: &SocketAddr,
+        initiator_params: &HandshakeParams,
+        responder_params: &HandshakeParams,
+    ) -> Result<BytesMut, io::Error> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(addr, &handle).unwrap();
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| NoiseHandshake::listen(responder_params, stream.unwrap().0))
+            .and_then(|framed| framed.into_future().map_err(|(e, _)| e))
+            .map(|(msg, _)| msg.unwrap());
+
+        let client = TcpStream::connect(addr, &handle)
+            .and_then(|stream| NoiseHandshake::send(initiator_params, stream))
+            .and_then(|framed| framed.send(BytesMut::from("ping")));
+
+        core.run(server.join(client)).map(|(msg, _)| msg)
+    }
+
     fn send_handshake(addr: &SocketAddr, step: HandshakeStep) -> Result<(), io::Error> {
         let mut core = Core::new().unwrap();
         let handle = core.handle();
 
-        let params = HandshakeParams {
-            max_message_len: 1024,
-        };
+        let params = HandshakeParams::new(1024);
 
         let stream = TcpStream::connect(&addr, &handle)
             .and_then(|sock| {
@@ -326,14 +1465,28 @@ mod tests {
         core.run(stream)
     }
 
+    /// Params bound to the preferred protocol along with the prefix of the initiator's first message.
+    fn negotiated(params: &HandshakeParams) -> (HandshakeParams, Vec<u8>) {
+        let selection = params.negotiation().preferred().unwrap();
+        let negotiation_data = params.negotiation().negotiation_data(&selection).unwrap();
+        let mut prefix = vec![0u8; 4];
+        LittleEndian::write_u32(&mut prefix, params.network_id);
+        prefix.extend_from_slice(&negotiation_data);
+        (params.with_selection(selection, prologue(&negotiation_data)), prefix)
+    }
+
     fn send_bad_handshake(params: &HandshakeParams, stream: TcpStream, step: HandshakeStep) -> HandshakeResult {
         let max_message_len = params.max_message_len;
-        let mut noise = NoiseWrapper::initiator(params);
+        let (params, negotiation_data) = negotiated(params);
+        let mut noise = NoiseWrapper::initiator(&params);
         let framed
         = write_bad_handshake_msg(&mut noise, 1, &step)
-            .and_then(|(len, buf)| write(stream, &buf, len))
-            .and_then(|(stream, _msg)| read(stream))
-            .and_then(move |(stream, msg)| {
+            .and_then(move |(len, buf)| {
+                let msg = [&negotiation_data[..], &buf[..len]].concat();
+                write_tagged(stream, XX_HANDSHAKE, &msg, msg.len())
+            })
+            .and_then(|(stream, _msg)| read_tagged(stream))
+            .and_then(move |(stream, _tag, msg)| {
                 read_handshake_msg(&msg, &mut noise)
                     .and_then(move |_| {
                         write_bad_handshake_msg(&mut noise, 2, &step)
@@ -351,12 +1504,14 @@ mod tests {
 
     fn listen_bad_handshake(stream: TcpStream, params: &HandshakeParams, step: HandshakeStep) -> HandshakeResult {
         let max_message_len = params.max_message_len;
-        let mut noise = NoiseWrapper::responder(params);
-        let framed = read(stream).and_then(move |(stream, msg)| {
+        let (params, negotiation_data) = negotiated(params);
+        let mut noise = NoiseWrapper::responder(&params);
+        let framed = read_tagged(stream).and_then(move |(stream, _tag, msg)| {
+            let msg = msg.get(negotiation_data.len()..).unwrap_or(&[]).to_vec();
             read_handshake_msg(&msg, &mut noise)
                 .and_then(move |_| {
                     write_bad_handshake_msg(&mut noise, 1, &step)
-                        .and_then(|(len, buf)| write(stream, &buf, len))
+                        .and_then(|(len, buf)| write_tagged(stream, HANDSHAKE_ACCEPTED, &buf, len))
                         .and_then(|(stream, _msg)| read(stream))
                         .and_then(move |(stream, msg)| {
                             noise.read_handshake_msg(&msg)?;
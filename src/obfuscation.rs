@@ -0,0 +1,100 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Obfuscated framing of transport packets.
+//!
+//! In this mode the length of each packet is encrypted with a separate cipher,
+//! which keys are derived from the secret of the session (see `NoiseWrapper`),
+//! so a passive observer can't find packet boundaries in the stream. It is best
+//! combined with padding, see `PaddingPolicy`.
+//!
+//! Handshake messages are out of scope: their 2-byte lengths and handshake type tags
+//! are sent in clear, since no secret is shared before the handshake. The obfuscation
+//! hides sizes of the transport messages, not the fact that Noise is used.
+
+use byteorder::{ByteOrder, LittleEndian};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use wrapper::{NoiseError, NOISE_PACKET_HEADER_LENGTH, TAG_LENGTH};
+
+pub const ENCRYPTED_HEADER_LENGTH: usize = NOISE_PACKET_HEADER_LENGTH + TAG_LENGTH;
+const HEADER_KEY_LENGTH: usize = 32;
+
+static INITIATOR_HEADER_LABEL: &[u8] = b"noise initiator header key";
+static RESPONDER_HEADER_LABEL: &[u8] = b"noise responder header key";
+
+/// Cipher used to encrypt lengths of transport packets.
+pub struct HeaderCipher {
+    send_key: [u8; HEADER_KEY_LENGTH],
+    receive_key: [u8; HEADER_KEY_LENGTH],
+    send_nonce: u64,
+    receive_nonce: u64,
+}
+
+impl HeaderCipher {
+    /// Derives header keys for both directions from the secret of the session.
+    pub fn new(session_secret: &[u8], initiator: bool) -> Self {
+        let initiator_key = derive_key(session_secret, INITIATOR_HEADER_LABEL);
+        let responder_key = derive_key(session_secret, RESPONDER_HEADER_LABEL);
+        let (send_key, receive_key) = if initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+
+        HeaderCipher {
+            send_key,
+            receive_key,
+            send_nonce: 0,
+            receive_nonce: 0,
+        }
+    }
+
+    /// Writes encrypted `len` to `out`, which should be `ENCRYPTED_HEADER_LENGTH` bytes long.
+    pub fn encrypt_len(&mut self, len: u16, out: &mut [u8]) {
+        let mut header = [0u8; NOISE_PACKET_HEADER_LENGTH];
+        LittleEndian::write_u16(&mut header, len);
+
+        let (ciphertext, tag) = out.split_at_mut(NOISE_PACKET_HEADER_LENGTH);
+        Self::cipher(&self.send_key, self.send_nonce).encrypt(&header, ciphertext, tag);
+        self.send_nonce += 1;
+    }
+
+    /// Decrypts length from `ENCRYPTED_HEADER_LENGTH` bytes of `header`.
+    pub fn decrypt_len(&mut self, header: &[u8]) -> Result<u16, NoiseError> {
+        let (ciphertext, tag) = header[..ENCRYPTED_HEADER_LENGTH].split_at(NOISE_PACKET_HEADER_LENGTH);
+        let mut len = [0u8; NOISE_PACKET_HEADER_LENGTH];
+        if !Self::cipher(&self.receive_key, self.receive_nonce).decrypt(ciphertext, &mut len, tag) {
+            return Err(NoiseError::new("Unable to decrypt packet header"));
+        }
+        self.receive_nonce += 1;
+        Ok(LittleEndian::read_u16(&len))
+    }
+
+    fn cipher(key: &[u8], nonce: u64) -> ChaCha20Poly1305 {
+        let mut nonce_bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut nonce_bytes, nonce);
+        ChaCha20Poly1305::new(key, &nonce_bytes, &[])
+    }
+}
+
+fn derive_key(session_secret: &[u8], label: &[u8]) -> [u8; HEADER_KEY_LENGTH] {
+    let mut prk = [0u8; HEADER_KEY_LENGTH];
+    hkdf_extract(Sha256::new(), &[], session_secret, &mut prk);
+    let mut key = [0u8; HEADER_KEY_LENGTH];
+    hkdf_expand(Sha256::new(), &prk, label, &mut key);
+    key
+}
@@ -0,0 +1,106 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rand::{thread_rng, Rng};
+
+use std::cmp;
+
+/// Policy of padding transport packets to resist traffic analysis.
+///
+/// Padding is added inside the encrypted payload, sizes refer to the whole
+/// packet on the wire, including its length header.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaddingPolicy {
+    None,
+    /// Pad to the next power of two.
+    PowerOfTwo,
+    /// Pad to the multiple of the given size.
+    FixedBucket(usize),
+    /// Pad with random number of bytes up to the given one.
+    Random(usize),
+    /// Pad to a randomly chosen size from the list, among those which can fit the packet.
+    RandomBucket(Vec<usize>),
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        PaddingPolicy::None
+    }
+}
+
+impl PaddingPolicy {
+    pub fn is_none(&self) -> bool {
+        *self == PaddingPolicy::None
+    }
+
+    /// Returns size of the packet after padding, which never exceeds `max_len`.
+    pub fn padded_len(&self, len: usize, max_len: usize) -> usize {
+        let padded_len = match *self {
+            PaddingPolicy::None => len,
+            PaddingPolicy::PowerOfTwo => len.next_power_of_two(),
+            PaddingPolicy::FixedBucket(0) => len,
+            PaddingPolicy::FixedBucket(bucket) => (len.saturating_add(bucket - 1) / bucket).saturating_mul(bucket),
+            PaddingPolicy::Random(max_padding) => {
+                // Padding beyond `max_len` would be cut anyway, clamping also avoids overflow.
+                let max_padding = cmp::min(max_padding, max_len.saturating_sub(len));
+                len + thread_rng().gen_range(0, max_padding + 1)
+            }
+            PaddingPolicy::RandomBucket(ref buckets) => {
+                let fitting = buckets
+                    .iter()
+                    .cloned()
+                    .filter(|&bucket| bucket >= len && bucket <= max_len)
+                    .collect::<Vec<_>>();
+                thread_rng().choose(&fitting).cloned().unwrap_or(len)
+            }
+        };
+        cmp::max(len, cmp::min(padded_len, max_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use padding::PaddingPolicy;
+
+    const MAX_LEN: usize = 65_537;
+
+    #[test]
+    fn test_padded_len() {
+        assert_eq!(PaddingPolicy::None.padded_len(100, MAX_LEN), 100);
+        assert_eq!(PaddingPolicy::PowerOfTwo.padded_len(100, MAX_LEN), 128);
+        assert_eq!(PaddingPolicy::PowerOfTwo.padded_len(128, MAX_LEN), 128);
+        assert_eq!(PaddingPolicy::PowerOfTwo.padded_len(40_000, MAX_LEN), MAX_LEN);
+        assert_eq!(PaddingPolicy::FixedBucket(512).padded_len(100, MAX_LEN), 512);
+        assert_eq!(PaddingPolicy::FixedBucket(512).padded_len(513, MAX_LEN), 1024);
+
+        for _ in 0..100 {
+            let len = PaddingPolicy::Random(50).padded_len(100, MAX_LEN);
+            assert!(len >= 100 && len <= 150);
+
+            let len = PaddingPolicy::RandomBucket(vec![64, 256, 1024]).padded_len(100, MAX_LEN);
+            assert!(len == 256 || len == 1024);
+        }
+        assert_eq!(PaddingPolicy::RandomBucket(vec![64]).padded_len(100, MAX_LEN), 100);
+    }
+
+    #[test]
+    fn test_padded_len_overflow() {
+        for _ in 0..100 {
+            let len = PaddingPolicy::Random(usize::max_value()).padded_len(100, MAX_LEN);
+            assert!(len >= 100 && len <= MAX_LEN);
+        }
+        assert_eq!(PaddingPolicy::Random(usize::max_value()).padded_len(MAX_LEN, MAX_LEN), MAX_LEN);
+        assert_eq!(PaddingPolicy::FixedBucket(usize::max_value()).padded_len(100, MAX_LEN), MAX_LEN);
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protection against replayed messages with explicit nonces.
+
+/// Default number of the latest nonces tracked by the window.
+pub const DEFAULT_REPLAY_WINDOW_SIZE: usize = 64;
+/// Maximal number of the tracked nonces, its bitmap takes 8 KiB.
+pub const MAX_REPLAY_WINDOW_SIZE: usize = 65_536;
+const WORD_BITS: usize = 64;
+
+/// Sliding window of the received nonces.
+///
+/// Nonce is accepted if it is higher than any received one or it is one of the
+/// `size` latest nonces and hasn't been received yet. Bitmap is used as a ring
+/// indexed by the nonce, so the window moves without shifting it.
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    size: u64,
+    highest: Option<u64>,
+    bitmap: Vec<u64>,
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        ReplayWindow::new(DEFAULT_REPLAY_WINDOW_SIZE)
+    }
+}
+
+impl ReplayWindow {
+    /// Creates window which tracks `size` latest nonces, at least one nonce
+    /// and at most `MAX_REPLAY_WINDOW_SIZE` nonces are tracked.
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1).min(MAX_REPLAY_WINDOW_SIZE);
+        let words = (size + WORD_BITS - 1) / WORD_BITS;
+        ReplayWindow {
+            size: size as u64,
+            highest: None,
+            bitmap: vec![0; words],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Returns `true` if the message with `nonce` can be accepted.
+    pub fn check(&self, nonce: u64) -> bool {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => return true,
+        };
+        if nonce > highest {
+            return true;
+        }
+
+        highest - nonce < self.size && !self.is_set(nonce)
+    }
+
+    /// Marks `nonce` as received, should be called only after the message has been authenticated.
+    pub fn update(&mut self, nonce: u64) {
+        match self.highest {
+            Some(highest) if nonce <= highest => {
+                if highest - nonce < self.size {
+                    self.set(nonce);
+                }
+            }
+            Some(highest) => {
+                // Bits of the nonces which leave the window are reused for the new ones.
+                if nonce - highest >= self.capacity() {
+                    for word in &mut self.bitmap {
+                        *word = 0;
+                    }
+                } else {
+                    for skipped in highest + 1..nonce {
+                        self.clear(skipped);
+                    }
+                }
+                self.set(nonce);
+                self.highest = Some(nonce);
+            }
+            None => {
+                self.set(nonce);
+                self.highest = Some(nonce);
+            }
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        (self.bitmap.len() * WORD_BITS) as u64
+    }
+
+    fn position(&self, nonce: u64) -> (usize, u64) {
+        let bit = nonce % self.capacity();
+        ((bit / WORD_BITS as u64) as usize, 1 << (bit % WORD_BITS as u64))
+    }
+
+    fn is_set(&self, nonce: u64) -> bool {
+        let (word, mask) = self.position(nonce);
+        self.bitmap[word] & mask != 0
+    }
+
+    fn set(&mut self, nonce: u64) {
+        let (word, mask) = self.position(nonce);
+        self.bitmap[word] |= mask;
+    }
+
+    fn clear(&mut self, nonce: u64) {
+        let (word, mask) = self.position(nonce);
+        self.bitmap[word] &= !mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+    use replay::{ReplayWindow, DEFAULT_REPLAY_WINDOW_SIZE, MAX_REPLAY_WINDOW_SIZE};
+    use std::collections::HashSet;
+
+    const SIZES: [usize; 6] = [1, 2, 63, 64, 100, 1024];
+
+    fn accept(window: &mut ReplayWindow, nonce: u64) -> bool {
+        let accepted = window.check(nonce);
+        if accepted {
+            window.update(nonce);
+        }
+        accepted
+    }
+
+    /// Straightforward model of the window which remembers every nonce.
+    struct Model {
+        size: u64,
+        received: HashSet<u64>,
+        highest: Option<u64>,
+    }
+
+    impl Model {
+        fn accept(&mut self, nonce: u64) -> bool {
+            let fresh = match self.highest {
+                Some(highest) => nonce > highest || highest - nonce < self.size,
+                None => true,
+            };
+            if !fresh || !self.received.insert(nonce) {
+                return false;
+            }
+            self.highest = Some(self.highest.map_or(nonce, |highest| highest.max(nonce)));
+            true
+        }
+    }
+
+    #[test]
+    fn test_default_size() {
+        assert_eq!(ReplayWindow::default().size(), DEFAULT_REPLAY_WINDOW_SIZE);
+        assert_eq!(ReplayWindow::new(usize::max_value()).size(), MAX_REPLAY_WINDOW_SIZE);
+        assert_eq!(ReplayWindow::new(0).size(), 1);
+    }
+
+    #[test]
+    fn test_in_order() {
+        for &size in &SIZES {
+            let mut window = ReplayWindow::new(size);
+            for nonce in 0..3000 {
+                assert!(accept(&mut window, nonce));
+                assert!(!accept(&mut window, nonce));
+            }
+        }
+    }
+
+    #[test]
+    fn test_first_nonce_isnt_zero() {
+        let mut window = ReplayWindow::new(64);
+        assert!(accept(&mut window, 1000));
+        assert!(accept(&mut window, 999));
+        assert!(accept(&mut window, 1000 - 63));
+        assert!(!accept(&mut window, 1000 - 64));
+    }
+
+    #[test]
+    fn test_reordered_within_window() {
+        for &size in &SIZES {
+            let size = size as u64;
+            let mut window = ReplayWindow::new(size as usize);
+            let highest = 5000;
+            assert!(accept(&mut window, highest));
+            // Every nonce of the window is accepted once, in reverse order.
+            for nonce in (highest - size + 1..highest).rev() {
+                assert!(accept(&mut window, nonce), "size {}, nonce {}", size, nonce);
+            }
+            for nonce in highest - size + 1..highest + 1 {
+                assert!(!accept(&mut window, nonce), "size {}, nonce {}", size, nonce);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stale() {
+        for &size in &SIZES {
+            let size = size as u64;
+            let mut window = ReplayWindow::new(size as usize);
+            assert!(accept(&mut window, 10_000));
+            assert!(!window.check(10_000 - size));
+            assert!(!window.check(0));
+            assert!(window.check(10_000 - size + 1) || size == 1);
+
+            // Nonce which has just left the window isn't accepted even if it was never received.
+            assert!(accept(&mut window, 10_001));
+            assert!(!accept(&mut window, 10_001 - size));
+        }
+    }
+
+    #[test]
+    fn test_jump_forward() {
+        for &size in &SIZES {
+            let size = size as u64;
+            let mut window = ReplayWindow::new(size as usize);
+            for nonce in 0..size {
+                assert!(accept(&mut window, nonce));
+            }
+
+            // Jump larger than the bitmap resets it.
+            let highest = 1_000_000;
+            assert!(accept(&mut window, highest));
+            for nonce in highest - size + 1..highest {
+                assert!(window.check(nonce), "size {}, nonce {}", size, nonce);
+            }
+
+            // Partial jump keeps the nonces which are still in the window.
+            let offset = size / 4;
+            let received = highest - offset;
+            if offset > 0 {
+                assert!(accept(&mut window, received));
+            }
+            let next = highest + size / 2 + 1;
+            assert!(accept(&mut window, next));
+            if next - received < size {
+                assert!(!window.check(received));
+            }
+            for nonce in highest + 1..next {
+                assert!(window.check(nonce), "size {}, nonce {}", size, nonce);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nonce_bounds() {
+        let mut window = ReplayWindow::new(64);
+        assert!(accept(&mut window, 0));
+        assert!(accept(&mut window, u64::max_value()));
+        assert!(!accept(&mut window, u64::max_value()));
+        assert!(accept(&mut window, u64::max_value() - 63));
+        assert!(!accept(&mut window, u64::max_value() - 64));
+        assert!(!accept(&mut window, 0));
+    }
+
+    #[test]
+    fn test_unverified_nonce_isnt_recorded() {
+        let mut window = ReplayWindow::new(64);
+        assert!(accept(&mut window, 10));
+        // Message failed authentication, so the window isn't updated.
+        assert!(window.check(1000));
+        assert!(accept(&mut window, 5));
+        assert!(accept(&mut window, 1000));
+    }
+
+    #[test]
+    fn test_matches_model() {
+        let mut rng = thread_rng();
+        for &size in &SIZES {
+            let mut window = ReplayWindow::new(size);
+            let mut model = Model {
+                size: size as u64,
+                received: HashSet::new(),
+                highest: None,
+            };
+
+            let mut base = 0u64;
+            for _ in 0..20_000 {
+                // Mostly reordered nonces near the front, with occasional jumps and old ones.
+                let nonce = match rng.gen_range(0, 10) {
+                    0 => base.saturating_sub(rng.gen_range(0, 3 * size as u64 + 1)),
+                    1 => {
+                        base += rng.gen_range(0, 4 * size as u64 + 1);
+                        base
+                    }
+                    _ => {
+                        base += 1;
+                        (base + rng.gen_range(0, size as u64 + 1)).saturating_sub(size as u64 / 2)
+                    }
+                };
+                assert_eq!(
+                    accept(&mut window, nonce),
+                    model.accept(nonce),
+                    "size {}, nonce {}",
+                    size,
+                    nonce
+                );
+            }
+        }
+    }
+}
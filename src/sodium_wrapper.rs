@@ -1,16 +1,53 @@
 use rand::{thread_rng, Rng};
+use snow::types::{Cipher, Dh, Hash, Random};
+use snow::{CryptoResolver, DefaultResolver};
+use snow::params::{CipherChoice, DHChoice, HashChoice};
+use curve448::Dh448;
+use kem::{Kem, KEM_SECRET_LENGTH};
+use wrapper::NoiseError;
 
 use sodiumoxide::crypto::aead::chacha20poly1305 as sodium_chacha20poly1305;
 use sodiumoxide::crypto::hash::sha256 as sodium_sha256;
 use sodiumoxide::crypto::scalarmult::curve25519 as sodium_curve25519;
 
-// Random data generator.
-pub struct SodiumRandom;
+/// Crypto resolver which performs Diffie-Hellman with libsodium, provides Curve448
+/// of this crate and falls back to the default snow implementations for everything else.
+pub struct SodiumResolver {
+    parent: DefaultResolver,
+}
 
-pub trait Random {
-    fn fill_bytes(&mut self, out: &mut [u8]);
+impl SodiumResolver {
+    pub fn new() -> Self {
+        SodiumResolver {
+            parent: DefaultResolver {},
+        }
+    }
 }
 
+impl CryptoResolver for SodiumResolver {
+    fn resolve_rng(&self) -> Option<Box<Random>> {
+        Some(Box::new(SodiumRandom::default()))
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<Dh>> {
+        match *choice {
+            DHChoice::Curve25519 => Some(Box::new(SodiumDh25519::default())),
+            DHChoice::Ed448 => Some(Box::new(Dh448::default())),
+        }
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<Hash>> {
+        self.parent.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<Cipher>> {
+        self.parent.resolve_cipher(choice)
+    }
+}
+
+// Random data generator.
+pub struct SodiumRandom;
+
 impl Default for SodiumRandom {
     fn default() -> SodiumRandom {
         SodiumRandom {}
@@ -40,7 +77,7 @@ impl Default for SodiumDh25519 {
     }
 }
 
-impl SodiumDh25519 {
+impl Dh for SodiumDh25519 {
     fn name(&self) -> &'static str {
         "25519"
     }
@@ -78,21 +115,79 @@ impl SodiumDh25519 {
         &self.privkey[0..32]
     }
 
-    fn dh(&self, pubkey: &[u8], out: &mut [u8]) {
-        let pubkey = sodium_curve25519::GroupElement::from_slice(&pubkey[0..32])
-            .expect("Can't construct public key for Dh25519");
-        let result =
-            sodium_curve25519::scalarmult(&self.privkey, &pubkey).expect("Can't calculate dh");
+    /// Fails if the public key is malformed or the shared secret is all zeros.
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        let pubkey = sodium_curve25519::GroupElement::from_slice(&pubkey[0..32]).ok_or(())?;
+        let result = sodium_curve25519::scalarmult(&self.privkey, &pubkey)?;
 
         out[..32].copy_from_slice(&result[0..32]);
+        Ok(())
+    }
+}
+
+
+/// Test KEM built on X25519 with libsodium.
+///
+/// It isn't post-quantum and adds nothing to the Diffie-Hellman of the handshake,
+/// it is used to test the KEM hook, see `kem` module. Don't use it in production.
+#[derive(Debug, Default)]
+pub struct SodiumTestKem25519;
+
+impl SodiumTestKem25519 {
+    fn shared_secret(
+        privkey: &sodium_curve25519::Scalar,
+        pubkey: &[u8],
+        ciphertext: &[u8],
+        recipient: &[u8],
+    ) -> Result<[u8; KEM_SECRET_LENGTH], NoiseError> {
+        let pubkey = sodium_curve25519::GroupElement::from_slice(pubkey)
+            .ok_or_else(|| NoiseError::new("Wrong length of the KEM public key"))?;
+        let shared = sodium_curve25519::scalarmult(privkey, &pubkey)
+            .map_err(|_| NoiseError::new("Invalid KEM public key"))?;
+
+        let mut input = shared[..].to_vec();
+        input.extend_from_slice(ciphertext);
+        input.extend_from_slice(recipient);
+        let mut secret = [0u8; KEM_SECRET_LENGTH];
+        secret.copy_from_slice(&sodium_sha256::hash(&input)[..]);
+        Ok(secret)
     }
 }
 
+impl Kem for SodiumTestKem25519 {
+    fn name(&self) -> &'static str {
+        "X25519"
+    }
+
+    fn generate_keypair(&self) -> (Vec<u8>, Vec<u8>) {
+        let mut keypair = SodiumDh25519::default();
+        keypair.generate(&mut SodiumRandom::default());
+        (keypair.pubkey().to_vec(), keypair.privkey().to_vec())
+    }
+
+    fn encapsulate(&self, public_key: &[u8]) -> Result<(Vec<u8>, [u8; KEM_SECRET_LENGTH]), NoiseError> {
+        let mut ephemeral = SodiumDh25519::default();
+        ephemeral.generate(&mut SodiumRandom::default());
+        let ciphertext = ephemeral.pubkey().to_vec();
+        let secret = Self::shared_secret(&ephemeral.privkey, public_key, &ciphertext, public_key)?;
+        Ok((ciphertext, secret))
+    }
+
+    fn decapsulate(&self, secret_key: &[u8], ciphertext: &[u8]) -> Result<[u8; KEM_SECRET_LENGTH], NoiseError> {
+        let privkey = sodium_curve25519::Scalar::from_slice(secret_key)
+            .ok_or_else(|| NoiseError::new("Wrong length of the KEM secret key"))?;
+        let public_key = sodium_curve25519::scalarmult_base(&privkey);
+        Self::shared_secret(&privkey, ciphertext, ciphertext, &public_key[..])
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use kem::Kem;
     use sodium_wrapper::SodiumDh25519;
-    use sodium_wrapper::{Random, SodiumRandom};
+    use sodium_wrapper::SodiumTestKem25519;
+    use sodium_wrapper::SodiumRandom;
+    use snow::types::{Dh, Random};
     use sodiumoxide::crypto::sign::{gen_keypair, keypair_from_seed, PublicKey, SecretKey};
     use sodiumoxide::crypto::sign::ed25519::Seed;
 
@@ -153,14 +248,29 @@ mod tests {
         let (public_key_r, secret_key_r) = (dh_cloned_r.pubkey(), dh_cloned_r.privkey());
 
         let mut output_i = [0u8; 32];
-        dh_i.dh(public_key_r, &mut output_i);
+        dh_i.dh(public_key_r, &mut output_i).unwrap();
 
         let mut output_r = [0u8; 32];
-        dh_r.dh(public_key_i, &mut output_r);
+        dh_r.dh(public_key_i, &mut output_r).unwrap();
 
         assert_eq!(output_i, output_r);
     }
 
+    #[test]
+    fn test_kem_25519() {
+        let kem = SodiumTestKem25519;
+        let (public_key, secret_key) = kem.generate_keypair();
+        let (ciphertext, secret) = kem.encapsulate(&public_key).unwrap();
+        assert_eq!(kem.decapsulate(&secret_key, &ciphertext).unwrap(), secret);
+
+        let (other_public_key, _) = kem.generate_keypair();
+        let (_, other_secret) = kem.encapsulate(&other_public_key).unwrap();
+        assert_ne!(other_secret, secret);
+
+        assert!(kem.encapsulate(&public_key[1..]).is_err());
+        assert!(kem.decapsulate(&secret_key, &[0; 32]).is_err());
+    }
+
     #[test]
     fn test_convert_ed_to_curve_dh() {
         // Generate Ed25519 keys for initiator and responder.
@@ -175,12 +285,12 @@ mod tests {
         let mut keypair_i: SodiumDh25519 = Default::default();
         keypair_i.set(&secret_key_i[..32]);
         let mut output_i = [0u8; 32];
-        keypair_i.dh(public_key_r.as_ref(), &mut output_i);
+        keypair_i.dh(public_key_r.as_ref(), &mut output_i).unwrap();
 
         let mut keypair_r: SodiumDh25519 = Default::default();
         keypair_r.set(&secret_key_r[..32]);
         let mut output_r = [0u8; 32];
-        keypair_r.dh(public_key_i.as_ref(), &mut output_r);
+        keypair_r.dh(public_key_i.as_ref(), &mut output_r).unwrap();
 
         assert_eq!(output_i, output_r);
     }
@@ -0,0 +1,248 @@
+// Copyright 2018 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming transfer of data which doesn't fit into memory.
+//!
+//! Data is sent as a sequence of transport messages, each one fits into one Noise packet.
+//! The first byte of every message is a continuation flag, since it is a part of the
+//! encrypted payload, truncation of the sequence can't go unnoticed.
+//! Streamed chunks share the connection with regular messages, so the application
+//! has to know when the peer starts streaming (e.g. by using a dedicated substream).
+
+use bytes::{BufMut, BytesMut};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use tokio_io::AsyncRead;
+use wrapper::NOISE_MAX_PAYLOAD_LENGTH;
+
+use std::cmp;
+use std::io::{self, Read};
+
+pub const CHUNK_LAST: u8 = 0;
+pub const CHUNK_CONTINUED: u8 = 1;
+/// Maximal amount of data in one chunk, so that chunk with its flag fits into one Noise packet.
+pub const MAX_CHUNK_LENGTH: usize = NOISE_MAX_PAYLOAD_LENGTH - 1;
+
+/// Reads `source` until the end and sends it to `sink` chunk by chunk.
+///
+/// Returned future resolves to the source and the sink once the last chunk is flushed.
+pub fn send_stream<R, S>(source: R, sink: S) -> SendStream<R, S>
+where
+    R: AsyncRead,
+    S: Sink<SinkItem = BytesMut, SinkError = io::Error>,
+{
+    SendStream {
+        source: Some(source),
+        sink: Some(sink),
+        pending: None,
+        read_buf: vec![0u8; MAX_CHUNK_LENGTH],
+        finished: false,
+    }
+}
+
+pub struct SendStream<R, S> {
+    source: Option<R>,
+    sink: Option<S>,
+    pending: Option<BytesMut>,
+    read_buf: Vec<u8>,
+    finished: bool,
+}
+
+impl<R, S> SendStream<R, S>
+where
+    R: AsyncRead,
+    S: Sink<SinkItem = BytesMut, SinkError = io::Error>,
+{
+    fn chunk(flag: u8, data: &[u8]) -> BytesMut {
+        let mut chunk = BytesMut::with_capacity(data.len() + 1);
+        chunk.put_u8(flag);
+        chunk.put_slice(data);
+        chunk
+    }
+}
+
+impl<R, S> Future for SendStream<R, S>
+where
+    R: AsyncRead,
+    S: Sink<SinkItem = BytesMut, SinkError = io::Error>,
+{
+    type Item = (R, S);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(R, S), io::Error> {
+        loop {
+            if let Some(chunk) = self.pending.take() {
+                let sink = self.sink.as_mut().expect("Polled SendStream after completion");
+                if let AsyncSink::NotReady(chunk) = sink.start_send(chunk)? {
+                    self.pending = Some(chunk);
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            if self.finished {
+                try_ready!(self.sink.as_mut().unwrap().poll_complete());
+                return Ok(Async::Ready((self.source.take().unwrap(), self.sink.take().unwrap())));
+            }
+
+            let len = {
+                let source = self.source.as_mut().expect("Polled SendStream after completion");
+                match source.poll_read(&mut self.read_buf)? {
+                    Async::Ready(len) => len,
+                    Async::NotReady => {
+                        // Let already queued chunks go out while waiting for the source.
+                        self.sink.as_mut().unwrap().poll_complete()?;
+                        return Ok(Async::NotReady);
+                    }
+                }
+            };
+
+            // Empty read means the end of the source, which is marked with the last chunk.
+            let flag = if len == 0 {
+                self.finished = true;
+                CHUNK_LAST
+            } else {
+                CHUNK_CONTINUED
+            };
+            self.pending = Some(Self::chunk(flag, &self.read_buf[..len]));
+        }
+    }
+}
+
+/// Plaintext of the streamed data, available incrementally as it arrives.
+///
+/// Reading returns `0` after the last chunk, if the connection ends earlier,
+/// `UnexpectedEof` error is returned.
+pub struct ChunkReader<S> {
+    stream: S,
+    buf: BytesMut,
+    finished: bool,
+}
+
+impl<S> ChunkReader<S>
+where
+    S: Stream<Item = BytesMut, Error = io::Error>,
+{
+    pub fn new(stream: S) -> Self {
+        ChunkReader {
+            stream,
+            buf: BytesMut::new(),
+            finished: false,
+        }
+    }
+
+    /// Returns `true` if the last chunk has been received.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns underlying stream, so it can be used for the following messages.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> Read for ChunkReader<S>
+where
+    S: Stream<Item = BytesMut, Error = io::Error>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.buf.is_empty() {
+            if self.finished {
+                return Ok(0);
+            }
+
+            let mut chunk = match self.stream.poll()? {
+                Async::Ready(Some(chunk)) => chunk,
+                Async::Ready(None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed before the last chunk",
+                    ))
+                }
+                Async::NotReady => return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block")),
+            };
+
+            if chunk.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty chunk"));
+            }
+            let flag = chunk.split_to(1)[0];
+            match flag {
+                CHUNK_LAST => self.finished = true,
+                CHUNK_CONTINUED => {}
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unknown chunk flag {}", flag),
+                    ))
+                }
+            }
+            self.buf = chunk;
+        }
+
+        let len = cmp::min(buf.len(), self.buf.len());
+        buf[..len].copy_from_slice(&self.buf.split_to(len));
+        Ok(len)
+    }
+}
+
+impl<S> AsyncRead for ChunkReader<S>
+where
+    S: Stream<Item = BytesMut, Error = io::Error>,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+    use noise_main::NoiseHandshake;
+    use std::io::{self, Cursor};
+    use std::net::SocketAddr;
+    use streaming::{send_stream, ChunkReader, MAX_CHUNK_LENGTH};
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_io::io::read_to_end;
+    use wrapper::HandshakeParams;
+
+    fn run_streaming(addr: &SocketAddr, data: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let params = HandshakeParams::new(1024);
+
+        let listener = TcpListener::bind(addr, &handle).unwrap();
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| NoiseHandshake::listen(&params, stream.unwrap().0))
+            .and_then(|framed| read_to_end(ChunkReader::new(framed), Vec::new()))
+            .map(|(_, data)| data);
+
+        let client = TcpStream::connect(addr, &handle)
+            .and_then(|stream| NoiseHandshake::send(&params, stream))
+            .and_then(move |framed| send_stream(Cursor::new(data), framed));
+
+        core.run(server.join(client)).map(|(received, _)| received)
+    }
+
+    #[test]
+    fn test_streaming_large_data() {
+        let data: Vec<u8> = (0..MAX_CHUNK_LENGTH * 10 + 7).map(|i| i as u8).collect();
+        let received = run_streaming(&"127.0.0.1:45201".parse().unwrap(), data.clone()).unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_streaming_empty_data() {
+        let received = run_streaming(&"127.0.0.1:45202".parse().unwrap(), Vec::new()).unwrap();
+        assert!(received.is_empty());
+    }
+}
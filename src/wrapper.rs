@@ -13,56 +13,478 @@
 // limitations under the License.
 
 use byteorder::{ByteOrder, LittleEndian};
-use bytes::BytesMut;
-use snow::{NoiseBuilder, Session};
+use bytes::{BufMut, BytesMut};
+use cookie::CookieChecker;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use keypair::KeyPair;
+use known_peers::KnownPeers;
+use kem::SharedKem;
+use limiter::HandshakeLimiter;
+use negotiation::{Cipher, Curve, Hash, Negotiation, ParameterSet, Selection, MAX_OFFER_LENGTH};
+use obfuscation::{HeaderCipher, ENCRYPTED_HEADER_LENGTH};
+use padding::PaddingPolicy;
+use replay::{ReplayWindow, DEFAULT_REPLAY_WINDOW_SIZE, MAX_REPLAY_WINDOW_SIZE};
+use snow::{CryptoResolver, NoiseBuilder, Session};
+use sodium_wrapper::SodiumResolver;
+use tokio_core::reactor::Handle;
 
+use std::cmp;
 use std::fmt;
 use std::fmt::{Error, Formatter};
 use std::io;
+use std::iter;
+use std::time::Duration;
 
 pub const NOISE_MAX_MESSAGE_LENGTH: usize = 65_535;
 pub const TAG_LENGTH: usize = 16;
-pub const NOISE_HEADER_LENGTH: usize = 4;
+pub const PACKET_FLAG_LENGTH: usize = 1;
+/// Maximal length of the message data carried by one packet.
+pub const NOISE_MAX_PAYLOAD_LENGTH: usize = NOISE_MAX_MESSAGE_LENGTH - TAG_LENGTH - PACKET_FLAG_LENGTH;
+pub const NOISE_PACKET_HEADER_LENGTH: usize = 2;
 pub const HANDSHAKE_HEADER_LENGTH: usize = 2;
 pub const NOISE_MIN_HANDSHAKE_MESSAGE_LENGTH: usize = 32;
 
+/// Flags authenticated inside each transport packet, which mark the message boundary.
+const LAST_PACKET: u8 = 0;
+const CONTINUED_PACKET: u8 = 1;
+/// Padded packet has 2 bytes padding length after the flags, padding follows the data.
+const PADDED_PACKET: u8 = 2;
+const PADDING_HEADER_LENGTH: usize = 2;
+/// Control packet carries a frame of the transport itself instead of the application data.
+const CONTROL_PACKET: u8 = 4;
+/// Maximal length of the control frame, which is always sent in one packet.
+pub const MAX_CONTROL_PAYLOAD_LENGTH: usize = 256;
+/// Salt of the keying material exporter, separates exported keys from the internal ones.
+const EXPORTER_SALT: &[u8] = b"noise keying material exporter";
+/// Salt of the session secret, which is derived from the transport keys.
+const SESSION_SECRET_SALT: &[u8] = b"noise session secret";
+const SHA256_LENGTH: usize = 32;
+/// Maximal length of the exported keying material, limited by HKDF.
+pub const MAX_EXPORTED_LENGTH: usize = 255 * SHA256_LENGTH;
+/// Length of the explicit nonce which prefixes each datagram.
+pub const NONCE_LENGTH: usize = 8;
+/// Maximal length of the message carried by one datagram.
+pub const MAX_DATAGRAM_PAYLOAD_LENGTH: usize = NOISE_MAX_MESSAGE_LENGTH - TAG_LENGTH;
+
+/// Network of the peers which haven't set it explicitly.
+pub const DEFAULT_NETWORK_ID: u32 = 0;
+
+pub const PSK_LENGTH: usize = 32;
+/// Greatest psk modifier position valid for `XX` pattern.
+pub const MAX_PSK_LOCATION: u8 = 3;
+/// Greatest psk modifier position valid for `IK` pattern.
+pub const IK_MAX_PSK_LOCATION: u8 = 2;
+/// Psk modifier position taken by the secret of the KEM handshake.
+pub const KEM_PSK_LOCATION: u8 = 3;
+// Replaced with the KEM secret before it's used.
+static KEM_PSK_PLACEHOLDER: [u8; PSK_LENGTH] = [0; PSK_LENGTH];
+
 // We choose XX pattern since it provides mutual authentication and
 // transmission of static public keys.
 // See: https://noiseprotocol.org/noise.html#interactive-patterns
-static PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+static XX_PATTERN: &str = "XX";
+
+// IK pattern is used by returning peers which already know the static key
+// of the responder, it saves one round trip compared to XX.
+// See: https://noiseprotocol.org/noise.html#noise-pipes
+static IK_PATTERN: &str = "IK";
+
+// XXfallback pattern is used when the responder can't read `IK` message, e.g. because
+// its static key has changed. It reuses the ephemeral key of the initiator sent in `IK`
+// message, so the handshake completes in the same round trip.
+// See: https://noiseprotocol.org/noise.html#the-fallback-modifier
+static XX_FALLBACK_PATTERN: &str = "XXfallback";
 
 #[derive(Debug, Clone)]
 /// Params needed to establish secured connection using Noise Protocol.
 pub struct HandshakeParams {
+    /// Maximal length of the transport message which is split into several packets,
+    /// longer messages are rejected while they are reassembled.
     pub max_message_len: u32,
+    pub public_key: Vec<u8>,
+    /// Static key of the remote peer, if it is known from previous connections.
+    /// When set, the initiator tries `IK` handshake first.
+    pub remote_key: Option<Vec<u8>>,
+    /// Store of the static keys of the peers we have connected to.
+    /// Consulted by the initiator when `remote_key` isn't set and updated
+    /// after each successful handshake.
+    pub known_peers: Option<KnownPeers>,
+    /// Node id of the remote peer, its key is looked up in `known_peers`
+    /// before the key of the peer address.
+    pub remote_node_id: Option<String>,
+    /// Limits of the incoming handshakes, checked by the responder before any crypto is done.
+    pub limiter: Option<HandshakeLimiter>,
+    /// Makes the responder require cookies from the initiators when it is under load.
+    pub cookies: Option<CookieChecker>,
+    /// Time the responder waits for the handshake to complete, along with the reactor
+    /// the timer runs on. Expired handshakes fail and release their limiter permits.
+    pub handshake_timeout: Option<(Duration, Handle)>,
+    /// Enables obfuscated framing of transport messages with encrypted packet lengths.
+    /// Handshake messages aren't obfuscated, see `obfuscation` module.
+    pub obfuscation: bool,
+    /// Padding of transport packets, applied inside the encrypted payload.
+    pub padding: PaddingPolicy,
+    /// Number of the latest nonces tracked against replays in stateless transport mode,
+    /// messages reordered within this window are accepted. Windows larger than
+    /// `MAX_REPLAY_WINDOW_SIZE` are cut to it.
+    pub replay_window: usize,
+    /// Identifier of the network, peers from different networks are rejected by the handshake.
+    pub network_id: u32,
+    /// Context bound into the handshake, e.g. application name or genesis hash,
+    /// peers with different prologues fail the handshake.
+    pub prologue: Vec<u8>,
+    // Fields below are used to build sessions, so they are changed only
+    // through the validating setters.
+    //
+    // Curve of the static keys, every offered parameter set uses it.
+    curve: Curve,
+    secret_key: Vec<u8>,
+    // Pre-shared keys along with their psk modifier positions,
+    // e.g. `(3, key)` turns `XX` into `XXpsk3`.
+    psks: Vec<(u8, [u8; PSK_LENGTH])>,
+    // KEM whose secret is mixed into `XX` handshakes as `psk3`, see `kem` module.
+    kem: Option<SharedKem>,
+    // Protocol versions and Noise parameter sets offered by the initiator
+    // and accepted by the responder.
+    negotiation: Negotiation,
+    // Result of the negotiation, set only for the handshake itself.
+    selection: Option<Selection>,
+    negotiation_prologue: Vec<u8>,
+}
+
+impl HandshakeParams {
+    /// Creates params with freshly generated static keypair.
+    pub fn new(max_message_len: u32) -> Self {
+        Self::with_curve(Curve::Curve25519, max_message_len)
+    }
+
+    /// Creates params with freshly generated static keypair on `curve`.
+    pub fn with_curve(curve: Curve, max_message_len: u32) -> Self {
+        Self::with_keypair(KeyPair::generate(curve), max_message_len)
+    }
+
+    /// Creates params with the given static keypair, e.g. loaded from the key file.
+    pub fn with_keypair(keypair: KeyPair, max_message_len: u32) -> Self {
+        let negotiation = Negotiation {
+            parameter_sets: vec![ParameterSet {
+                curve: keypair.curve,
+                ..ParameterSet::default()
+            }],
+            ..Negotiation::default()
+        };
+
+        HandshakeParams {
+            max_message_len,
+            public_key: keypair.public_key,
+            remote_key: None,
+            known_peers: None,
+            remote_node_id: None,
+            limiter: None,
+            cookies: None,
+            handshake_timeout: None,
+            obfuscation: false,
+            padding: PaddingPolicy::None,
+            replay_window: DEFAULT_REPLAY_WINDOW_SIZE,
+            network_id: DEFAULT_NETWORK_ID,
+            prologue: Vec::new(),
+            curve: keypair.curve,
+            secret_key: keypair.secret_key,
+            psks: Vec::new(),
+            kem: None,
+            negotiation,
+            selection: None,
+            negotiation_prologue: Vec::new(),
+        }
+    }
+
+    /// Curve of the static keys, every offered parameter set uses it.
+    pub fn curve(&self) -> Curve {
+        self.curve
+    }
+
+    /// KEM whose secret is mixed into `XX` handshakes, peers which don't use the same KEM are rejected.
+    pub fn kem(&self) -> Option<&SharedKem> {
+        self.kem.as_ref()
+    }
+
+    /// Protocol versions and Noise parameter sets offered by the initiator
+    /// and accepted by the responder.
+    pub fn negotiation(&self) -> &Negotiation {
+        &self.negotiation
+    }
+
+    pub fn set_remote_key(&mut self, remote_key: Vec<u8>) {
+        self.remote_key = Some(remote_key);
+    }
+
+    pub fn set_known_peers(&mut self, known_peers: KnownPeers) {
+        self.known_peers = Some(known_peers);
+    }
+
+    pub fn set_remote_node_id<S: Into<String>>(&mut self, node_id: S) {
+        self.remote_node_id = Some(node_id.into());
+    }
+
+    pub fn set_limiter(&mut self, limiter: HandshakeLimiter) {
+        self.limiter = Some(limiter);
+    }
+
+    pub fn set_cookies(&mut self, cookies: CookieChecker) {
+        self.cookies = Some(cookies);
+    }
+
+    pub fn set_handshake_timeout(&mut self, timeout: Duration, handle: &Handle) {
+        self.handshake_timeout = Some((timeout, handle.clone()));
+    }
+
+    /// Mixes the secret of `kem` into `XX` handshakes as `psk3`, see `kem` module.
+    /// The handshake is post-quantum only if `kem` is. Fails if `psk3` is already used.
+    pub fn set_kem(&mut self, kem: SharedKem) -> Result<(), NoiseError> {
+        if self.psks.iter().any(|&(location, _)| location == KEM_PSK_LOCATION) {
+            return Err(NoiseError::new("psk3 is taken by the KEM handshake"));
+        }
+        self.kem = Some(kem);
+        Ok(())
+    }
+
+    pub fn set_network_id(&mut self, network_id: u32) {
+        self.network_id = network_id;
+    }
+
+    pub fn set_prologue(&mut self, prologue: &[u8]) {
+        self.prologue = prologue.to_vec();
+    }
+
+    /// Fails if `replay_window` is greater than `MAX_REPLAY_WINDOW_SIZE`.
+    pub fn set_replay_window(&mut self, replay_window: usize) -> Result<(), NoiseError> {
+        if replay_window > MAX_REPLAY_WINDOW_SIZE {
+            return Err(NoiseError::new(format!(
+                "Replay window is too large: {} > {}",
+                replay_window, MAX_REPLAY_WINDOW_SIZE
+            )));
+        }
+        self.replay_window = replay_window;
+        Ok(())
+    }
+
+    /// Uses only the given cipher and hash along with the curve of the static keys,
+    /// peers which don't support them are rejected.
+    pub fn set_cipher_suite(&mut self, cipher: Cipher, hash: Hash) -> Result<(), NoiseError> {
+        let curve = self.curve;
+        self.set_parameter_sets(vec![ParameterSet::new(curve, cipher, hash)])
+    }
+
+    /// Sets parameter sets offered by the initiator and accepted by the responder,
+    /// the most preferred first. Fails if the crypto resolver doesn't support any of them
+    /// or they use other curve than the static keys.
+    pub fn set_parameter_sets(&mut self, parameter_sets: Vec<ParameterSet>) -> Result<(), NoiseError> {
+        if parameter_sets.is_empty() {
+            return Err(NoiseError::new("At least one parameter set is required"));
+        }
+        if parameter_sets.len() > MAX_OFFER_LENGTH {
+            return Err(NoiseError::new(format!("At most {} parameter sets can be offered", MAX_OFFER_LENGTH)));
+        }
+        let resolver = resolver();
+        for parameter_set in &parameter_sets {
+            if parameter_set.curve != self.curve {
+                return Err(NoiseError::new(format!(
+                    "Parameter set {} doesn't match curve {} of the static keys",
+                    parameter_set.name(),
+                    self.curve.name()
+                )));
+            }
+            parameter_set.check_supported(&*resolver)?;
+        }
+        self.negotiation.parameter_sets = parameter_sets;
+        Ok(())
+    }
+
+    /// Sets protocol versions and parameter sets, the most preferred first.
+    /// Parameter sets are checked as in `set_parameter_sets`.
+    pub fn set_negotiation(&mut self, negotiation: Negotiation) -> Result<(), NoiseError> {
+        if negotiation.versions.is_empty() {
+            return Err(NoiseError::new("At least one protocol version is required"));
+        }
+        negotiation.check_lengths()?;
+        self.set_parameter_sets(negotiation.parameter_sets)?;
+        self.negotiation.versions = negotiation.versions;
+        Ok(())
+    }
+
+    /// Returns params of the handshake with the negotiated protocol, which is bound
+    /// to the negotiation by `prologue`.
+    pub fn with_selection(&self, selection: Selection, prologue: Vec<u8>) -> Self {
+        HandshakeParams {
+            selection: Some(selection),
+            negotiation_prologue: prologue,
+            ..self.clone()
+        }
+    }
+
+    /// Adds pre-shared key mixed into the handshake at the given psk modifier position.
+    pub fn add_psk(&mut self, location: u8, psk: &[u8]) -> Result<(), NoiseError> {
+        if location > MAX_PSK_LOCATION {
+            return Err(NoiseError::new(format!("Wrong psk modifier position {}", location)));
+        }
+        if psk.len() != PSK_LENGTH {
+            return Err(NoiseError::new(format!(
+                "Pre-shared key should be {} bytes long, got {}",
+                PSK_LENGTH,
+                psk.len()
+            )));
+        }
+        if self.psks.iter().any(|&(l, _)| l == location) {
+            return Err(NoiseError::new(format!("Duplicate psk modifier position {}", location)));
+        }
+        if location == KEM_PSK_LOCATION && self.kem.is_some() {
+            return Err(NoiseError::new("psk3 is taken by the KEM handshake"));
+        }
+
+        let mut key = [0u8; PSK_LENGTH];
+        key.copy_from_slice(psk);
+        self.psks.push((location, key));
+        self.psks.sort_by_key(|&(location, _)| location);
+        Ok(())
+    }
+
+    /// Returns `true` if psk modifiers allow to use `IK` pattern.
+    pub fn supports_ik(&self) -> bool {
+        self.psks.iter().all(|&(location, _)| location <= IK_MAX_PSK_LOCATION)
+    }
+}
+
+/// Decrypted transport payload.
+#[derive(Debug, PartialEq)]
+pub enum Payload {
+    /// Message of the application.
+    Message(BytesMut),
+    /// Control frame, e.g. keepalive ping, which is interpreted by the codec.
+    Control(BytesMut),
 }
 
 /// Wrapper around noise session to provide latter convenient interface.
 pub struct NoiseWrapper {
     pub session: Session,
+    // Handshake messages which carry psk tokens, counted from 1.
+    psk_messages: Vec<usize>,
+    // Handshake messages written and read so far.
+    handshake_messages: usize,
+    framing: Framing,
+    // Stored on transition into transport mode, since the session doesn't keep it afterwards.
+    handshake_hash: Option<Vec<u8>>,
+    exporter: Option<KeyingMaterialExporter>,
+}
+
+/// State of the transport framing which isn't a part of Noise session.
+#[derive(Default)]
+struct Framing {
+    // Decrypted packets of the message which hasn't been received completely.
+    partial_msg: BytesMut,
+    // Limit of `partial_msg`, messages of sessions created by `from_session` aren't limited.
+    max_message_len: Option<usize>,
+    obfuscation: bool,
+    padding: PaddingPolicy,
+    header_cipher: Option<HeaderCipher>,
+    // Length of the packet which header has already been decrypted.
+    pending_packet_len: Option<usize>,
+    // Nonce of the next datagram sent in stateless transport mode.
+    next_nonce: u64,
+    replay_window: ReplayWindow,
+}
+
+impl Framing {
+    fn header_len(&self) -> usize {
+        match self.header_cipher {
+            Some(_) => ENCRYPTED_HEADER_LENGTH,
+            None => NOISE_PACKET_HEADER_LENGTH,
+        }
+    }
 }
 
 impl NoiseWrapper {
+    pub fn from_session(session: Session) -> Self {
+        NoiseWrapper {
+            session,
+            psk_messages: Vec::new(),
+            handshake_messages: 0,
+            framing: Framing::default(),
+            handshake_hash: None,
+            exporter: None,
+        }
+    }
+
     pub fn responder(params: &HandshakeParams) -> Self {
-        let builder: NoiseBuilder = Self::noise_builder(params);
-        let private_key = builder.generate_private_key().unwrap();
-        let session = builder
-            .local_private_key(&private_key)
+        let prologue = Self::prologue(params, &[]);
+        let session = Self::noise_builder(params, XX_PATTERN, &prologue)
             .build_responder()
             .unwrap();
 
-        NoiseWrapper { session }
+        Self::new(params, session)
     }
 
     pub fn initiator(params: &HandshakeParams) -> Self {
-        let builder: NoiseBuilder = Self::noise_builder(params);
-        let private_key = builder.generate_private_key().unwrap();
-        let session = builder
-            .local_private_key(&private_key)
+        let prologue = Self::prologue(params, &[]);
+        let session = Self::noise_builder(params, XX_PATTERN, &prologue)
+            .build_initiator()
+            .unwrap();
+
+        Self::new(params, session)
+    }
+
+    pub fn ik_responder(params: &HandshakeParams) -> Self {
+        let prologue = Self::prologue(params, &[]);
+        let session = Self::noise_builder(params, IK_PATTERN, &prologue)
+            .build_responder()
+            .unwrap();
+
+        Self::new(params, session)
+    }
+
+    /// `IK` initiator with the given ephemeral secret key, which is kept by the caller
+    /// in case the responder asks for `XXfallback`.
+    pub fn ik_initiator(params: &HandshakeParams, remote_key: &[u8], ephemeral_key: &[u8]) -> Self {
+        let prologue = Self::prologue(params, &[]);
+        let session = Self::noise_builder(params, IK_PATTERN, &prologue)
+            .remote_public_key(remote_key)
+            .local_ephemeral_key(ephemeral_key)
+            .build_initiator()
+            .unwrap();
+
+        Self::new(params, session)
+    }
+
+    /// `XXfallback` session of the `IK` initiator after the responder has failed
+    /// to read `ik_message`, which has been sent with `ephemeral_key`.
+    ///
+    /// The rejected `IK` message is bound into the prologue.
+    pub fn fallback_initiator(params: &HandshakeParams, ik_message: &[u8], ephemeral_key: &[u8]) -> Self {
+        let prologue = Self::prologue(params, ik_message);
+        let session = Self::noise_builder(params, XX_FALLBACK_PATTERN, &prologue)
+            .local_ephemeral_key(ephemeral_key)
             .build_initiator()
             .unwrap();
 
-        NoiseWrapper { session }
+        Self::new(params, session)
+    }
+
+    /// `XXfallback` session of the responder which has failed to read `ik_message`,
+    /// seeded with the ephemeral key at the start of the message.
+    ///
+    /// Fails if the message is too short to contain the ephemeral key.
+    pub fn fallback_responder(params: &HandshakeParams, ik_message: &[u8]) -> Result<Self, NoiseError> {
+        let key_len = params.curve.key_len();
+        if ik_message.len() < key_len {
+            return Err(NoiseError::new("IK message is too short to contain the ephemeral key"));
+        }
+
+        let prologue = Self::prologue(params, ik_message);
+        let session = Self::noise_builder(params, XX_FALLBACK_PATTERN, &prologue)
+            .remote_ephemeral_key(&ik_message[..key_len])
+            .build_responder()
+            .map_err(|e| NoiseError::new(format!("Can't build fallback session: {:?}", e.0)))?;
+
+        Ok(Self::new(params, session))
     }
 
     pub fn read_handshake_msg(&mut self, input: &[u8]) -> Result<(usize, Vec<u8>), NoiseError> {
@@ -72,7 +494,16 @@ impl NoiseWrapper {
             return Err(NoiseError::new("Wrong handshake message length"))
         }
 
-        self.read(input, NOISE_MAX_MESSAGE_LENGTH)
+        // Mismatch of pre-shared keys is noticed only on the message which carries psk token.
+        self.handshake_messages += 1;
+        let psk_message = self.psk_messages.contains(&self.handshake_messages);
+        self.read(input, NOISE_MAX_MESSAGE_LENGTH).map_err(|e| {
+            if psk_message {
+                NoiseError::new(format!("Handshake failed, pre-shared keys don't match: {}", e))
+            } else {
+                e
+            }
+        })
     }
 
     pub fn write_handshake_msg(&mut self) -> Result<(usize, Vec<u8>), NoiseError> {
@@ -80,70 +511,349 @@ impl NoiseWrapper {
         self.write(&[0u8])
     }
 
+    /// Writes handshake message carrying `payload`, which is returned by `read_handshake_msg`
+    /// of the peer. Payload is encrypted only if the handshake has already derived keys.
+    pub fn write_handshake_msg_with_payload(&mut self, payload: &[u8]) -> Result<(usize, Vec<u8>), NoiseError> {
+        self.write(payload)
+    }
+
+    /// Sets pre-shared key which has been agreed on during the handshake, before it is used.
+    pub fn set_psk(&mut self, location: u8, psk: &[u8]) -> Result<(), NoiseError> {
+        self.session
+            .set_psk(location as usize, psk)
+            .map_err(|e| NoiseError::new(format!("Unable to set pre-shared key: {:?}", e)))
+    }
+
+    /// Returns static key of the remote peer, available once it has been received during handshake.
+    pub fn remote_static_key(&self) -> Option<Vec<u8>> {
+        self.session.get_remote_static().map(|key| key.to_vec())
+    }
+
     pub fn into_transport_mode(self) -> Result<Self, NoiseError> {
         // Transition into transport mode after handshake is finished.
-        let session = self.session.into_transport_mode().map_err(|e| {
+        let NoiseWrapper {
+            mut session,
+            psk_messages,
+            handshake_messages,
+            mut framing,
+            ..
+        } = self;
+
+        let handshake_hash = finished_handshake_hash(&session)?;
+        let secret = session_secret(&mut session);
+        if framing.obfuscation {
+            framing.header_cipher = Some(HeaderCipher::new(&secret, session.is_initiator()));
+        }
+
+        let session = session.into_transport_mode().map_err(|e| {
             NoiseError::new(format!(
                 "Error when converting session into transport mode {}.",
                 e
             ))
         })?;
-        Ok(NoiseWrapper { session })
+        Ok(NoiseWrapper {
+            session,
+            psk_messages,
+            handshake_messages,
+            framing,
+            handshake_hash: Some(handshake_hash),
+            exporter: Some(KeyingMaterialExporter::new(&secret)),
+        })
     }
 
-    /// Decrypts `msg` using Noise session.
+    /// Transition into transport mode with explicit nonces, where messages
+    /// can be lost or reordered, e.g. when they are sent over UDP.
     ///
-    /// Decryption consists of the following steps:
-    /// 1. Message splits to packets of length smaller or equal to 65_535 bytes.
-    /// 2. Then each packet is decrypted by selected noise algorithm.
-    /// 3. Append all decrypted packets to `decoded_message`.
-    pub fn decrypt_msg(&mut self, len: usize, buf: &mut BytesMut) -> Result<BytesMut, io::Error> {
-        let data = buf.split_to(len + NOISE_HEADER_LENGTH).to_vec();
-        let data = &data[NOISE_HEADER_LENGTH..];
-        let mut decoded_message = vec![0u8; 0];
-
-        data.chunks(NOISE_MAX_MESSAGE_LENGTH).for_each(|msg| {
-            let len_to_read = if msg.len() == NOISE_MAX_MESSAGE_LENGTH {
-                msg.len() - TAG_LENGTH
-            } else {
+    /// Unlike `decrypt_msg`, which relies on the implicit counter of the session,
+    /// `decrypt_datagram` accepts any nonce within `HandshakeParams::replay_window`.
+    pub fn into_stateless_transport_mode(self) -> Result<Self, NoiseError> {
+        let NoiseWrapper {
+            mut session,
+            psk_messages,
+            handshake_messages,
+            framing,
+            ..
+        } = self;
+
+        let handshake_hash = finished_handshake_hash(&session)?;
+        let secret = session_secret(&mut session);
+        let session = session.into_stateless_transport_mode().map_err(|e| {
+            NoiseError::new(format!(
+                "Error when converting session into stateless transport mode {}.",
+                e
+            ))
+        })?;
+        Ok(NoiseWrapper {
+            session,
+            psk_messages,
+            handshake_messages,
+            framing,
+            handshake_hash: Some(handshake_hash),
+            exporter: Some(KeyingMaterialExporter::new(&secret)),
+        })
+    }
+
+    /// Returns hash of the finished handshake, available in transport mode.
+    ///
+    /// The hash is unique for the session, so authentication of the upper layer
+    /// can be bound to it, e.g. by signing it.
+    pub fn get_handshake_hash(&self) -> Option<&[u8]> {
+        self.handshake_hash.as_ref().map(|hash| hash.as_slice())
+    }
+
+    /// Returns exporter of the keying material, available in transport mode.
+    pub fn get_exporter(&self) -> Option<&KeyingMaterialExporter> {
+        self.exporter.as_ref()
+    }
+
+    /// Derives `len` bytes of secret keying material bound to the session, available
+    /// in transport mode. Different labels give independent keys.
+    pub fn export_keying_material(&self, label: &[u8], len: usize) -> Result<Vec<u8>, NoiseError> {
+        self.get_exporter()
+            .ok_or_else(|| NoiseError::new("Handshake isn't finished yet"))?
+            .export(label, len)
+    }
+
+    /// Encrypts `msg` into a datagram which is prefixed with its nonce.
+    pub fn encrypt_datagram(&mut self, msg: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        if msg.len() > MAX_DATAGRAM_PAYLOAD_LENGTH {
+            return Err(NoiseError::new(format!(
+                "Datagram payload is too long: {}",
                 msg.len()
-            };
+            )));
+        }
+
+        let nonce = self.framing.next_nonce;
+        self.framing.next_nonce = nonce
+            .checked_add(1)
+            .ok_or_else(|| NoiseError::new("Datagram nonces are exhausted"))?;
+
+        let mut datagram = vec![0u8; NONCE_LENGTH + msg.len() + TAG_LENGTH];
+        LittleEndian::write_u64(&mut datagram, nonce);
+        let len = self
+            .session
+            .write_message_with_nonce(nonce, msg, &mut datagram[NONCE_LENGTH..])
+            .map_err(|e| NoiseError::new(format!("Error while writing datagram: {:?}", e)))?;
+        datagram.truncate(NONCE_LENGTH + len);
+        Ok(datagram)
+    }
 
-            let (_, read_to) = self.read(msg, len_to_read).unwrap();
-            decoded_message.extend_from_slice(&read_to);
-        });
+    /// Decrypts the datagram, rejecting replayed and too old ones.
+    pub fn decrypt_datagram(&mut self, datagram: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        if datagram.len() < NONCE_LENGTH + TAG_LENGTH {
+            return Err(NoiseError::new(format!(
+                "Datagram is too short: {}",
+                datagram.len()
+            )));
+        }
 
-        Ok(BytesMut::from(decoded_message))
+        let nonce = LittleEndian::read_u64(datagram);
+        if !self.framing.replay_window.check(nonce) {
+            return Err(NoiseError::new(format!(
+                "Datagram with nonce {} is replayed or too old",
+                nonce
+            )));
+        }
+
+        let mut msg = vec![0u8; datagram.len() - NONCE_LENGTH];
+        let len = self
+            .session
+            .read_message_with_nonce(nonce, &datagram[NONCE_LENGTH..], &mut msg)
+            .map_err(|e| NoiseError::new(format!("Error while reading datagram: {:?}", e)))?;
+        msg.truncate(len);
+        // Window is moved only by authentic datagrams, so forged nonces can't shift it.
+        self.framing.replay_window.update(nonce);
+        Ok(msg)
+    }
+
+    /// Decrypts one message from `buf` using Noise session.
+    ///
+    /// Decryption consists of the following steps:
+    /// 1. Each complete packet is split from `buf` by its length header.
+    /// 2. Then the packet is decrypted in place by selected noise algorithm.
+    /// 3. The first byte of the plaintext tells whether it is the last packet of the message
+    /// and whether the packet is padded.
+    /// 4. Packets are collected until the last one, then the whole message is returned.
+    ///
+    /// Returns `None` if the message hasn't been received completely yet.
+    /// Control frames are skipped, use `decrypt_payload` to receive them.
+    pub fn decrypt_msg(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, io::Error> {
+        while let Some(payload) = self.decrypt_payload(buf)? {
+            if let Payload::Message(msg) = payload {
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decrypts either one message or one control frame from `buf`.
+    pub fn decrypt_payload(&mut self, buf: &mut BytesMut) -> Result<Option<Payload>, io::Error> {
+        while let Some((flags, data)) = self.decrypt_packet(buf)? {
+            if flags & CONTROL_PACKET != 0 {
+                return Ok(Some(Payload::Control(data)));
+            }
+
+            let last = flags & CONTINUED_PACKET == 0;
+            if self.framing.partial_msg.is_empty() && last {
+                return Ok(Some(Payload::Message(data)));
+            }
+
+            if let Some(max_len) = self.framing.max_message_len {
+                let len = self.framing.partial_msg.len() + data.len();
+                if len > max_len {
+                    self.framing.partial_msg.clear();
+                    return Err(NoiseError::new(format!("Message is too long: {} > {}", len, max_len)).into());
+                }
+            }
+            self.framing.partial_msg.extend_from_slice(&data);
+            if last {
+                return Ok(Some(Payload::Message(self.framing.partial_msg.take())));
+            }
+        }
+        Ok(None)
     }
 
     /// Encrypts `msg` using Noise session
     ///
     /// Encryption consists of the following steps:
     /// 1. Message splits to packets of length smaller or equal to 65_535 bytes.
-    /// 2. Then each packet is encrypted by selected noise algorithm.
-    /// 3. Result message: first 4 bytes is message length(`len').
-    /// 4. Append all encrypted packets in corresponding order.
-    /// 5. Write result message to `buf`
+    /// 2. Space for all encrypted packets is reserved in `buf` up front.
+    /// 3. Each packet starts with ciphertext length, which is encrypted in obfuscated mode.
+    /// 4. Plaintext of each packet is prefixed with the packet flags, so the message
+    /// boundary is authenticated, written into `buf` and encrypted there in place.
+    /// 5. If `PaddingPolicy` is set, padding is appended to the plaintext, so that
+    /// the packet on the wire has the size chosen by the policy.
     pub fn encrypt_msg(&mut self, msg: &[u8], buf: &mut BytesMut) -> Result<Option<()>, io::Error> {
-        let mut len = 0usize;
-        let mut encoded_message = vec![0u8; 0];
-
-        msg.chunks(NOISE_MAX_MESSAGE_LENGTH - TAG_LENGTH)
-            .for_each(|msg| {
-                let (written_bytes, written) = self.write(msg).unwrap();
-                encoded_message.extend_from_slice(&written);
-                len += written_bytes;
-            });
-
-        let mut msg_len_buf = vec![0u8; NOISE_HEADER_LENGTH];
-
-        LittleEndian::write_u32(&mut msg_len_buf, len as u32);
-        let encoded_message = &encoded_message[0..len];
-        msg_len_buf.extend_from_slice(encoded_message);
-        buf.extend_from_slice(&msg_len_buf);
+        self.encrypt(msg, 0, buf)
+    }
+
+    /// Encrypts control frame, which is sent in a single packet.
+    pub fn encrypt_control(&mut self, frame: &[u8], buf: &mut BytesMut) -> Result<Option<()>, io::Error> {
+        if frame.len() > MAX_CONTROL_PAYLOAD_LENGTH {
+            return Err(NoiseError::new(format!("Control frame is too long: {}", frame.len())).into());
+        }
+        self.encrypt(frame, CONTROL_PACKET, buf)
+    }
+
+    fn encrypt(&mut self, msg: &[u8], packet_flags: u8, buf: &mut BytesMut) -> Result<Option<()>, io::Error> {
+        let padded = !self.framing.padding.is_none();
+        let header_len = self.framing.header_len();
+        let max_chunk_len = if padded {
+            NOISE_MAX_PAYLOAD_LENGTH - PADDING_HEADER_LENGTH
+        } else {
+            NOISE_MAX_PAYLOAD_LENGTH
+        };
+
+        let packets = cmp::max(1, (msg.len() + max_chunk_len - 1) / max_chunk_len);
+        buf.reserve(msg.len() + packets * (header_len + PACKET_FLAG_LENGTH + TAG_LENGTH));
+
+        for packet in 0..packets {
+            let start = packet * max_chunk_len;
+            let chunk = &msg[start..cmp::min(msg.len(), start + max_chunk_len)];
+            let mut flags = packet_flags | if packet + 1 == packets { LAST_PACKET } else { CONTINUED_PACKET };
+
+            let mut plaintext_len = PACKET_FLAG_LENGTH + chunk.len();
+            let mut padding = 0;
+            if padded {
+                flags |= PADDED_PACKET;
+                let unpadded_len = header_len + PACKET_FLAG_LENGTH + PADDING_HEADER_LENGTH + chunk.len() + TAG_LENGTH;
+                let max_len = header_len + NOISE_MAX_MESSAGE_LENGTH;
+                padding = self.framing.padding.padded_len(unpadded_len, max_len) - unpadded_len;
+                plaintext_len += PADDING_HEADER_LENGTH + padding;
+            }
+
+            let packet_len = plaintext_len + TAG_LENGTH;
+            let mut header = [0u8; ENCRYPTED_HEADER_LENGTH];
+            match self.framing.header_cipher {
+                Some(ref mut header_cipher) => header_cipher.encrypt_len(packet_len as u16, &mut header),
+                None => LittleEndian::write_u16(&mut header, packet_len as u16),
+            }
+            buf.reserve(header_len + packet_len);
+            buf.put_slice(&header[..header_len]);
+
+            // Plaintext is written where the packet goes, followed by zeroed padding
+            // and space for the tag, and then encrypted in place.
+            let packet_start = buf.len();
+            buf.put_u8(flags);
+            if padded {
+                let mut padding_header = [0u8; PADDING_HEADER_LENGTH];
+                LittleEndian::write_u16(&mut padding_header, padding as u16);
+                buf.put_slice(&padding_header);
+            }
+            buf.put_slice(chunk);
+            buf.extend(iter::repeat(0).take(padding + TAG_LENGTH));
+
+            let written_bytes = self.session
+                .write_message_in_place(&mut buf[packet_start..], plaintext_len)
+                .map_err(|e| NoiseError::new(format!("Error while writing noise message: {:?}", e.0)))?;
+            debug_assert_eq!(written_bytes, packet_len);
+        }
+
         Ok(None)
     }
 
+    /// Decrypts one packet from `buf`, returns the packet flags and the packet payload.
+    fn decrypt_packet(&mut self, buf: &mut BytesMut) -> Result<Option<(u8, BytesMut)>, io::Error> {
+        let packet_len = match self.read_packet_len(buf)? {
+            Some(packet_len) => packet_len,
+            None => return Ok(None),
+        };
+        if buf.len() < packet_len {
+            return Ok(None);
+        }
+        self.framing.pending_packet_len = None;
+        let mut data = buf.split_to(packet_len);
+
+        let read_len = self.session
+            .read_message_in_place(&mut data)
+            .map_err(|e| NoiseError::new(format!("Error while reading noise message: {:?}", e.0)))?;
+        data.truncate(read_len);
+
+        let flags = data.split_to(PACKET_FLAG_LENGTH)[0];
+        if flags & !(CONTINUED_PACKET | PADDED_PACKET | CONTROL_PACKET) != 0
+            || flags & (CONTINUED_PACKET | CONTROL_PACKET) == CONTINUED_PACKET | CONTROL_PACKET
+        {
+            return Err(NoiseError::new(format!("Wrong packet flags {}", flags)).into());
+        }
+        if flags & PADDED_PACKET != 0 {
+            if data.len() < PADDING_HEADER_LENGTH {
+                return Err(NoiseError::new("Wrong padded packet length").into());
+            }
+            let padding = LittleEndian::read_u16(&data.split_to(PADDING_HEADER_LENGTH)) as usize;
+            if padding > data.len() {
+                return Err(NoiseError::new(format!("Wrong padding length {}", padding)).into());
+            }
+            let len = data.len() - padding;
+            data.truncate(len);
+        }
+        Ok(Some((flags, data)))
+    }
+
+    /// Reads length of the next packet and removes its header from `buf`.
+    fn read_packet_len(&mut self, buf: &mut BytesMut) -> Result<Option<usize>, io::Error> {
+        if let Some(packet_len) = self.framing.pending_packet_len {
+            return Ok(Some(packet_len));
+        }
+
+        let header_len = self.framing.header_len();
+        if buf.len() < header_len {
+            return Ok(None);
+        }
+
+        let header = buf.split_to(header_len);
+        let packet_len = match self.framing.header_cipher {
+            Some(ref mut header_cipher) => header_cipher.decrypt_len(&header)?,
+            None => LittleEndian::read_u16(&header),
+        };
+        let packet_len = packet_len as usize;
+        if packet_len < PACKET_FLAG_LENGTH + TAG_LENGTH {
+            return Err(NoiseError::new(format!("Wrong packet length {}", packet_len)).into());
+        }
+
+        self.framing.pending_packet_len = Some(packet_len);
+        Ok(Some(packet_len))
+    }
+
     fn read(&mut self, input: &[u8], len: usize) -> Result<(usize, Vec<u8>), NoiseError> {
         let mut buf = vec![0u8; len];
         info!("input.len() {}, len {}", input.len(), len);
@@ -159,6 +869,7 @@ impl NoiseWrapper {
     }
 
     fn write(&mut self, msg: &[u8]) -> Result<(usize, Vec<u8>), NoiseError> {
+        self.handshake_messages += 1;
         let mut buf = vec![0u8; NOISE_MAX_MESSAGE_LENGTH];
         let len = self.session
             .write_message(msg, &mut buf)
@@ -166,8 +877,137 @@ impl NoiseWrapper {
         Ok((len, buf))
     }
 
-    fn noise_builder(params: &HandshakeParams) -> NoiseBuilder {
-        NoiseBuilder::new(PARAMS.parse().unwrap())
+    fn new(params: &HandshakeParams, session: Session) -> Self {
+        NoiseWrapper {
+            session,
+            // `psk0` is at the start of the first message, `pskN` is at the end of N-th message.
+            psk_messages: params
+                .psks
+                .iter()
+                .map(|&(location, _)| cmp::max(location as usize, 1))
+                .collect(),
+            handshake_messages: 0,
+            handshake_hash: None,
+            exporter: None,
+            framing: Framing {
+                max_message_len: Some(params.max_message_len as usize),
+                obfuscation: params.obfuscation,
+                padding: params.padding.clone(),
+                replay_window: ReplayWindow::new(params.replay_window),
+                ..Framing::default()
+            },
+        }
+    }
+
+    /// Prologue of the session: network id and length-prefixed prologue of the params,
+    /// which are followed by the negotiation data and the `extra` data of the handshake.
+    fn prologue(params: &HandshakeParams, extra: &[u8]) -> Vec<u8> {
+        let mut prologue = vec![0u8; 8];
+        LittleEndian::write_u32(&mut prologue[..4], params.network_id);
+        LittleEndian::write_u32(&mut prologue[4..], params.prologue.len() as u32);
+        prologue.extend_from_slice(&params.prologue);
+        prologue.extend_from_slice(&params.negotiation_prologue);
+        if let Some(ref kem) = params.kem {
+            prologue.extend_from_slice(kem.name().as_bytes());
+        }
+        prologue.extend_from_slice(extra);
+        prologue
+    }
+
+    fn noise_builder<'a>(params: &'a HandshakeParams, pattern: &str, prologue: &'a [u8]) -> NoiseBuilder<'a> {
+        let mut psks: Vec<(u8, &'a [u8])> = params.psks.iter().map(|&(location, ref psk)| (location, &psk[..])).collect();
+        // Secret of the KEM handshake is set once the KEM ciphertext has been received.
+        if params.kem.is_some() && pattern == XX_PATTERN {
+            psks.push((KEM_PSK_LOCATION, &KEM_PSK_PLACEHOLDER[..]));
+        }
+
+        // Psk modifiers are appended to the pattern name, e.g. `XXpsk0+psk3` or `XXfallback+psk0`.
+        let mut modifiers = psks
+            .iter()
+            .map(|&(location, _)| format!("psk{}", location))
+            .collect::<Vec<_>>()
+            .join("+");
+        if pattern == XX_FALLBACK_PATTERN && !modifiers.is_empty() {
+            modifiers.insert(0, '+');
+        }
+        // Sessions created outside of the negotiated handshake use the preferred parameter set.
+        let parameter_set = params
+            .selection
+            .or_else(|| params.negotiation.preferred().ok())
+            .map_or(
+                ParameterSet {
+                    curve: params.curve,
+                    ..ParameterSet::default()
+                },
+                |selection| selection.parameter_set,
+            );
+        let noise_params = format!("Noise_{}{}_{}", pattern, modifiers, parameter_set.name());
+
+        psks.into_iter().fold(
+            NoiseBuilder::with_resolver(noise_params.parse().unwrap(), resolver())
+                .local_private_key(&params.secret_key)
+                .prologue(prologue),
+            |builder, (location, psk)| builder.psk(location, psk),
+        )
+    }
+}
+
+/// Crypto resolver of the sessions, parameter sets are checked against it.
+pub fn resolver() -> Box<CryptoResolver> {
+    Box::new(SodiumResolver::new())
+}
+
+/// Secret of the finished handshake, which keys of the framing and exported keying
+/// material are derived from.
+///
+/// Unlike the handshake hash it can't be computed by an observer, since it is extracted
+/// from the keys of both transport ciphers. HKDF doesn't reveal these keys.
+fn session_secret(session: &mut Session) -> [u8; SHA256_LENGTH] {
+    let (initiator_key, responder_key) = session.dangerously_get_raw_split();
+    let mut keys = initiator_key.to_vec();
+    keys.extend_from_slice(&responder_key);
+
+    let mut secret = [0u8; SHA256_LENGTH];
+    hkdf_extract(Sha256::new(), SESSION_SECRET_SALT, &keys, &mut secret);
+    secret
+}
+
+fn finished_handshake_hash(session: &Session) -> Result<Vec<u8>, NoiseError> {
+    session
+        .get_handshake_hash()
+        .map(|hash| hash.to_vec())
+        .map_err(|e| NoiseError::new(format!("Unable to get handshake hash: {:?}", e.0)))
+}
+
+/// Derives keying material of the finished session with HKDF-SHA256.
+///
+/// The material is derived from the secret of the session rather than from the public
+/// handshake hash, so it can be used as a key. It outlives the session, e.g. is kept
+/// by `NoiseConnection` after the session is moved into the codec.
+#[derive(Clone)]
+pub struct KeyingMaterialExporter {
+    prk: [u8; SHA256_LENGTH],
+}
+
+impl KeyingMaterialExporter {
+    fn new(session_secret: &[u8]) -> Self {
+        let mut prk = [0u8; SHA256_LENGTH];
+        hkdf_extract(Sha256::new(), EXPORTER_SALT, session_secret, &mut prk);
+        KeyingMaterialExporter { prk }
+    }
+
+    /// Derives `len` bytes of keying material, `label` is used as HKDF info.
+    pub fn export(&self, label: &[u8], len: usize) -> Result<Vec<u8>, NoiseError> {
+        if len > MAX_EXPORTED_LENGTH {
+            return Err(NoiseError::new(format!(
+                "Too much keying material requested: {}",
+                len
+            )));
+        }
+
+        let mut material = vec![0u8; len];
+        hkdf_expand(Sha256::new(), &self.prk, label, &mut material);
+        Ok(material)
     }
 }
 
@@ -200,3 +1040,501 @@ impl From<NoiseError> for io::Error {
         io::Error::new(io::ErrorKind::Other, e.message)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use bytes::BytesMut;
+    use keypair::KeyPair;
+    use negotiation::{Cipher, Curve, Hash, ParameterSet};
+    use obfuscation::ENCRYPTED_HEADER_LENGTH;
+    use padding::PaddingPolicy;
+    use wrapper::{HandshakeParams, NoiseWrapper, Payload, MAX_CONTROL_PAYLOAD_LENGTH, MAX_EXPORTED_LENGTH, NOISE_MAX_MESSAGE_LENGTH,
+                  NOISE_MAX_PAYLOAD_LENGTH, NOISE_PACKET_HEADER_LENGTH, NONCE_LENGTH, PSK_LENGTH, TAG_LENGTH};
+
+    // Limit of the messages which are split into several packets in tests.
+    const MAX_MESSAGE_LEN: u32 = 1 << 20;
+
+    fn transport_pair() -> (NoiseWrapper, NoiseWrapper) {
+        transport_pair_with(&HandshakeParams::new(MAX_MESSAGE_LEN), &HandshakeParams::new(MAX_MESSAGE_LEN))
+    }
+
+    fn transport_pair_with(
+        initiator_params: &HandshakeParams,
+        responder_params: &HandshakeParams,
+    ) -> (NoiseWrapper, NoiseWrapper) {
+        let (initiator, responder) = handshake_pair(initiator_params, responder_params);
+        (
+            initiator.into_transport_mode().unwrap(),
+            responder.into_transport_mode().unwrap(),
+        )
+    }
+
+    fn handshake_pair(
+        initiator_params: &HandshakeParams,
+        responder_params: &HandshakeParams,
+    ) -> (NoiseWrapper, NoiseWrapper) {
+        let mut initiator = NoiseWrapper::initiator(initiator_params);
+        let mut responder = NoiseWrapper::responder(responder_params);
+
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        responder.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, buf) = responder.write_handshake_msg().unwrap();
+        initiator.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        responder.read_handshake_msg(&buf[..len]).unwrap();
+
+        (initiator, responder)
+    }
+
+    #[test]
+    fn test_datagram_reorder_and_replay() {
+        let params = HandshakeParams::new(1024);
+        let (initiator, responder) = handshake_pair(&params, &params);
+        let mut initiator = initiator.into_stateless_transport_mode().unwrap();
+        let mut responder = responder.into_stateless_transport_mode().unwrap();
+
+        let datagrams: Vec<_> = (0..4u8)
+            .map(|i| initiator.encrypt_datagram(&[i; 100]).unwrap())
+            .collect();
+        assert_eq!(datagrams[0].len(), NONCE_LENGTH + 100 + TAG_LENGTH);
+
+        // Datagrams are decrypted in any order, but only once.
+        for &i in &[2, 0, 3, 1] {
+            assert_eq!(responder.decrypt_datagram(&datagrams[i]).unwrap(), vec![i as u8; 100]);
+        }
+        assert!(responder.decrypt_datagram(&datagrams[2]).is_err());
+
+        // Forged datagram doesn't move the replay window.
+        let mut forged = initiator.encrypt_datagram(&[5; 100]).unwrap();
+        forged[NONCE_LENGTH] ^= 1;
+        assert!(responder.decrypt_datagram(&forged).is_err());
+        assert!(responder.decrypt_datagram(&datagrams[0][..NONCE_LENGTH]).is_err());
+
+        let reply = responder.encrypt_datagram(&[6; 10]).unwrap();
+        assert_eq!(initiator.decrypt_datagram(&reply).unwrap(), vec![6; 10]);
+    }
+
+    #[test]
+    fn test_fallback_handshake() {
+        let initiator_params = HandshakeParams::new(1024);
+        let responder_params = HandshakeParams::new(1024);
+        let stale_key = HandshakeParams::new(1024).public_key;
+        let ephemeral = KeyPair::generate(Curve::Curve25519);
+
+        let mut initiator = NoiseWrapper::ik_initiator(&initiator_params, &stale_key, &ephemeral.secret_key);
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        let ik_message = buf[..len].to_vec();
+        assert!(NoiseWrapper::ik_responder(&responder_params).read_handshake_msg(&ik_message).is_err());
+
+        // Fallback takes two more messages, the first one answers the ephemeral key of `IK` message.
+        let mut responder = NoiseWrapper::fallback_responder(&responder_params, &ik_message).unwrap();
+        let mut initiator = NoiseWrapper::fallback_initiator(&initiator_params, &ik_message, &ephemeral.secret_key);
+        let (len, buf) = responder.write_handshake_msg().unwrap();
+        initiator.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        responder.read_handshake_msg(&buf[..len]).unwrap();
+        assert_eq!(initiator.remote_static_key(), Some(responder_params.public_key.clone()));
+        assert_eq!(responder.remote_static_key(), Some(initiator_params.public_key.clone()));
+
+        let mut initiator = initiator.into_stateless_transport_mode().unwrap();
+        let mut responder = responder.into_stateless_transport_mode().unwrap();
+        let datagram = initiator.encrypt_datagram(b"ping").unwrap();
+        assert_eq!(responder.decrypt_datagram(&datagram).unwrap(), b"ping".to_vec());
+
+        // Initiator which hasn't sent the ephemeral key can't read the answer.
+        let mut responder = NoiseWrapper::fallback_responder(&responder_params, &ik_message).unwrap();
+        let other_ephemeral = KeyPair::generate(Curve::Curve25519);
+        let mut initiator = NoiseWrapper::fallback_initiator(&initiator_params, &ik_message, &other_ephemeral.secret_key);
+        let (len, buf) = responder.write_handshake_msg().unwrap();
+        assert!(initiator.read_handshake_msg(&buf[..len]).is_err());
+
+        assert!(NoiseWrapper::fallback_responder(&responder_params, &ik_message[..16]).is_err());
+    }
+
+    #[test]
+    fn test_psk_mismatch_error() {
+        let mut initiator_params = HandshakeParams::new(1024);
+        let mut responder_params = HandshakeParams::new(1024);
+        initiator_params.add_psk(3, &[1; PSK_LENGTH]).unwrap();
+        responder_params.add_psk(3, &[2; PSK_LENGTH]).unwrap();
+
+        // Tampered message which doesn't carry psk token isn't blamed on pre-shared keys.
+        let mut initiator = NoiseWrapper::initiator(&initiator_params);
+        let mut responder = NoiseWrapper::responder(&responder_params);
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        responder.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, mut buf) = responder.write_handshake_msg().unwrap();
+        buf[len - 1] ^= 1;
+        let err = initiator.read_handshake_msg(&buf[..len]).unwrap_err();
+        assert!(!err.to_string().contains("pre-shared keys don't match"));
+
+        let mut initiator = NoiseWrapper::initiator(&initiator_params);
+        let mut responder = NoiseWrapper::responder(&responder_params);
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        responder.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, buf) = responder.write_handshake_msg().unwrap();
+        initiator.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        let err = responder.read_handshake_msg(&buf[..len]).unwrap_err();
+        assert!(err.to_string().contains("pre-shared keys don't match"));
+    }
+
+    #[test]
+    fn test_prologue_mismatch() {
+        let mut initiator_params = HandshakeParams::new(1024);
+        let mut responder_params = HandshakeParams::new(1024);
+        initiator_params.set_prologue(b"mainnet");
+        responder_params.set_prologue(b"mainnet");
+        transport_pair_with(&initiator_params, &responder_params);
+
+        responder_params.set_prologue(b"testnet");
+        let mut initiator = NoiseWrapper::initiator(&initiator_params);
+        let mut responder = NoiseWrapper::responder(&responder_params);
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        responder.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, buf) = responder.write_handshake_msg().unwrap();
+        assert!(initiator.read_handshake_msg(&buf[..len]).is_err());
+    }
+
+    #[test]
+    fn test_export_keying_material() {
+        let params = HandshakeParams::new(1024);
+        let (initiator, responder) = handshake_pair(&params, &params);
+        assert!(initiator.get_handshake_hash().is_none());
+        assert!(initiator.export_keying_material(b"label", 32).is_err());
+
+        let initiator = initiator.into_transport_mode().unwrap();
+        let responder = responder.into_transport_mode().unwrap();
+        assert_eq!(initiator.get_handshake_hash(), responder.get_handshake_hash());
+
+        let key = initiator.export_keying_material(b"label", 32).unwrap();
+        assert_eq!(key.len(), 32);
+        assert_eq!(key, responder.export_keying_material(b"label", 32).unwrap());
+        assert_ne!(key, initiator.export_keying_material(b"other label", 32).unwrap());
+        assert_eq!(initiator.export_keying_material(b"label", 64).unwrap()[..32], key[..]);
+        assert!(initiator.export_keying_material(b"label", MAX_EXPORTED_LENGTH + 1).is_err());
+
+        // Other session gives other keys.
+        let (other, _) = transport_pair();
+        assert_ne!(other.get_handshake_hash(), initiator.get_handshake_hash());
+        assert_ne!(other.export_keying_material(b"label", 32).unwrap(), key);
+    }
+
+    #[test]
+    fn test_cipher_suites() {
+        let hashes = [(Hash::Blake2s, 32), (Hash::Sha256, 32), (Hash::Sha512, 64), (Hash::Blake2b, 64)];
+        for &cipher in &[Cipher::ChaChaPoly, Cipher::AesGcm] {
+            for &(hash, hash_len) in &hashes {
+                let mut params = HandshakeParams::new(1024);
+                params.set_cipher_suite(cipher, hash).unwrap();
+                let (mut initiator, mut responder) = transport_pair_with(&params, &params);
+                assert_eq!(initiator.get_handshake_hash().unwrap().len(), hash_len);
+
+                let mut buf = BytesMut::new();
+                initiator.encrypt_msg(b"message", &mut buf).unwrap();
+                assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], b"message");
+            }
+        }
+        assert!(HandshakeParams::new(1024).set_parameter_sets(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_curve448() {
+        let mut params = HandshakeParams::with_curve(Curve::Curve448, 1024);
+        assert_eq!(params.public_key.len(), 56);
+        for &cipher in &[Cipher::ChaChaPoly, Cipher::AesGcm] {
+            params.set_cipher_suite(cipher, Hash::Sha512).unwrap();
+            let (mut initiator, mut responder) = transport_pair_with(&params, &params);
+            assert_eq!(initiator.get_handshake_hash().unwrap().len(), 64);
+            assert_eq!(responder.get_handshake_hash(), initiator.get_handshake_hash());
+
+            let mut buf = BytesMut::new();
+            initiator.encrypt_msg(b"message", &mut buf).unwrap();
+            assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], b"message");
+        }
+
+        // Parameter sets of the other curve don't match the static keys.
+        assert!(params.set_parameter_sets(vec![ParameterSet::default()]).is_err());
+    }
+
+    #[test]
+    fn test_cipher_suite_mismatch() {
+        let initiator_params = HandshakeParams::new(1024);
+        let mut responder_params = HandshakeParams::new(1024);
+        responder_params.set_cipher_suite(Cipher::AesGcm, Hash::Sha512).unwrap();
+
+        let mut initiator = NoiseWrapper::initiator(&initiator_params);
+        let mut responder = NoiseWrapper::responder(&responder_params);
+        let (len, buf) = initiator.write_handshake_msg().unwrap();
+        responder.read_handshake_msg(&buf[..len]).unwrap();
+        let (len, buf) = responder.write_handshake_msg().unwrap();
+        assert!(initiator.read_handshake_msg(&buf[..len]).is_err());
+    }
+
+    #[test]
+    fn test_datagram_replay_window_size() {
+        let mut params = HandshakeParams::new(1024);
+        params.set_replay_window(4).unwrap();
+        assert!(params.set_replay_window(usize::max_value()).is_err());
+        let (initiator, responder) = handshake_pair(&params, &params);
+        let mut initiator = initiator.into_stateless_transport_mode().unwrap();
+        let mut responder = responder.into_stateless_transport_mode().unwrap();
+
+        let datagrams: Vec<_> = (0..6u8)
+            .map(|i| initiator.encrypt_datagram(&[i]).unwrap())
+            .collect();
+        responder.decrypt_datagram(&datagrams[5]).unwrap();
+        assert!(responder.decrypt_datagram(&datagrams[1]).is_err());
+        assert_eq!(responder.decrypt_datagram(&datagrams[2]).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_msg() {
+        let (mut initiator, mut responder) = transport_pair();
+        let messages = vec![
+            vec![],
+            vec![1; 10],
+            vec![2; NOISE_MAX_PAYLOAD_LENGTH],
+            vec![3; NOISE_MAX_PAYLOAD_LENGTH * 3 + 1],
+        ];
+
+        let mut buf = BytesMut::new();
+        for msg in &messages {
+            initiator.encrypt_msg(msg, &mut buf).unwrap();
+        }
+        for msg in &messages {
+            assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], &msg[..]);
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decrypt_partial_msg() {
+        let (mut initiator, mut responder) = transport_pair();
+        let msg = vec![1; NOISE_MAX_PAYLOAD_LENGTH * 2];
+        let mut encrypted = BytesMut::new();
+        initiator.encrypt_msg(&msg, &mut encrypted).unwrap();
+
+        // Feed the message in small pieces, as it arrives from the socket.
+        let mut buf = BytesMut::new();
+        let mut decrypted = None;
+        while !encrypted.is_empty() {
+            let len = ::std::cmp::min(1000, encrypted.len());
+            buf.extend_from_slice(&encrypted.split_to(len));
+            assert!(decrypted.is_none());
+            decrypted = responder.decrypt_msg(&mut buf).unwrap();
+        }
+        assert_eq!(&decrypted.unwrap()[..], &msg[..]);
+    }
+
+    #[test]
+    fn test_decrypt_too_long_msg() {
+        let params = HandshakeParams::new(NOISE_MAX_PAYLOAD_LENGTH as u32 * 2);
+        let (mut initiator, mut responder) = transport_pair_with(&params, &params);
+        let mut buf = BytesMut::new();
+        initiator.encrypt_msg(&vec![1; NOISE_MAX_PAYLOAD_LENGTH * 2], &mut buf).unwrap();
+        assert_eq!(responder.decrypt_msg(&mut buf).unwrap().unwrap().len(), NOISE_MAX_PAYLOAD_LENGTH * 2);
+
+        // Message is rejected as soon as its packets exceed the limit.
+        initiator.encrypt_msg(&vec![1; NOISE_MAX_PAYLOAD_LENGTH * 4], &mut buf).unwrap();
+        buf.truncate(3 * (NOISE_PACKET_HEADER_LENGTH + NOISE_MAX_MESSAGE_LENGTH));
+        assert!(responder.decrypt_msg(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_tampered_packet_header() {
+        let msg = vec![1; NOISE_MAX_PAYLOAD_LENGTH * 2];
+        for bit in 0..NOISE_PACKET_HEADER_LENGTH * 8 {
+            let (mut initiator, mut responder) = transport_pair();
+            let mut buf = BytesMut::new();
+            initiator.encrypt_msg(&msg, &mut buf).unwrap();
+            initiator.encrypt_msg(&msg, &mut buf).unwrap();
+
+            buf[bit / 8] ^= 1 << (bit % 8);
+            let packet_len = LittleEndian::read_u16(&buf) as usize;
+
+            let res = responder.decrypt_msg(&mut buf);
+            if packet_len + NOISE_PACKET_HEADER_LENGTH <= buf.len() {
+                assert!(res.is_err(), "Tampered header bit {} wasn't detected", bit);
+            } else {
+                // Packet seems to be incomplete, tampered data is never returned.
+                assert!(res.unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampered_packet_flag() {
+        let (mut initiator, mut responder) = transport_pair();
+        let mut buf = BytesMut::new();
+        initiator.encrypt_msg(&[1; 10], &mut buf).unwrap();
+
+        // Flag is encrypted together with data, so it can't be flipped unnoticed.
+        buf[NOISE_PACKET_HEADER_LENGTH] ^= 1;
+        assert!(responder.decrypt_msg(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_dropped_last_packet() {
+        let (mut initiator, mut responder) = transport_pair();
+        let mut first = BytesMut::new();
+        initiator.encrypt_msg(&vec![1; NOISE_MAX_PAYLOAD_LENGTH + 1], &mut first).unwrap();
+        let mut second = BytesMut::new();
+        initiator.encrypt_msg(&[2; 10], &mut second).unwrap();
+
+        // Attacker removes the last packet of the first message.
+        let mut buf = first.split_to(NOISE_PACKET_HEADER_LENGTH + NOISE_MAX_MESSAGE_LENGTH);
+        buf.extend_from_slice(&second);
+        assert!(responder.decrypt_msg(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_control_frame() {
+        let (mut initiator, mut responder) = transport_pair();
+        let mut buf = BytesMut::new();
+        initiator.encrypt_control(&[1, 2], &mut buf).unwrap();
+        initiator.encrypt_msg(&[3; 10], &mut buf).unwrap();
+        initiator.encrypt_control(&[4], &mut buf).unwrap();
+        initiator.encrypt_msg(&[5; 10], &mut buf).unwrap();
+
+        assert_eq!(
+            responder.decrypt_payload(&mut buf).unwrap(),
+            Some(Payload::Control(BytesMut::from(&[1, 2][..])))
+        );
+        assert_eq!(
+            responder.decrypt_payload(&mut buf).unwrap(),
+            Some(Payload::Message(BytesMut::from(&[3; 10][..])))
+        );
+        // Control frames are invisible for those who only expect messages.
+        assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], &[5; 10]);
+        assert!(buf.is_empty());
+
+        assert!(initiator.encrypt_control(&[0; MAX_CONTROL_PAYLOAD_LENGTH + 1], &mut buf).is_err());
+    }
+
+    fn framed_pair(obfuscation: bool, padding: PaddingPolicy) -> (NoiseWrapper, NoiseWrapper) {
+        let mut initiator_params = HandshakeParams::new(MAX_MESSAGE_LEN);
+        let mut responder_params = HandshakeParams::new(MAX_MESSAGE_LEN);
+        initiator_params.obfuscation = obfuscation;
+        initiator_params.padding = padding.clone();
+        responder_params.obfuscation = obfuscation;
+        responder_params.padding = padding;
+        transport_pair_with(&initiator_params, &responder_params)
+    }
+
+    /// Encrypts messages of different sizes and returns lengths of the packets on the wire.
+    fn padded_lengths(initiator: &mut NoiseWrapper, responder: &mut NoiseWrapper) -> Vec<usize> {
+        [0, 1, 10, 100, 200, 1000, 3000]
+            .iter()
+            .map(|len| {
+                let msg = vec![1; *len];
+                let mut buf = BytesMut::new();
+                initiator.encrypt_msg(&msg, &mut buf).unwrap();
+                let wire_len = buf.len();
+
+                assert!(wire_len >= len + NOISE_PACKET_HEADER_LENGTH + TAG_LENGTH);
+                assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], &msg[..]);
+                wire_len
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_obfuscated_header() {
+        let (mut initiator, mut responder) = framed_pair(true, PaddingPolicy::None);
+        let msg = vec![1; 100];
+
+        let mut buf = BytesMut::new();
+        initiator.encrypt_msg(&msg, &mut buf).unwrap();
+        let packet_len = buf.len() - ENCRYPTED_HEADER_LENGTH;
+        assert_ne!(LittleEndian::read_u16(&buf) as usize, packet_len);
+
+        // Responder sends in the opposite direction with its own header key.
+        let mut reply = BytesMut::new();
+        responder.encrypt_msg(&msg, &mut reply).unwrap();
+        assert_ne!(&reply[..ENCRYPTED_HEADER_LENGTH], &buf[..ENCRYPTED_HEADER_LENGTH]);
+
+        assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], &msg[..]);
+        assert_eq!(&initiator.decrypt_msg(&mut reply).unwrap().unwrap()[..], &msg[..]);
+    }
+
+    #[test]
+    fn test_obfuscated_tampered_header() {
+        let (mut initiator, mut responder) = framed_pair(true, PaddingPolicy::None);
+        let mut buf = BytesMut::new();
+        initiator.encrypt_msg(&[1; 100], &mut buf).unwrap();
+
+        buf[0] ^= 1;
+        assert!(responder.decrypt_msg(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_obfuscated_padding() {
+        let buckets = vec![256, 1024, 4096];
+        let (mut initiator, mut responder) = framed_pair(true, PaddingPolicy::RandomBucket(buckets.clone()));
+
+        for len in &[0, 10, 200, 1000, 3000] {
+            let msg = vec![1; *len];
+            let mut buf = BytesMut::new();
+            initiator.encrypt_msg(&msg, &mut buf).unwrap();
+
+            assert!(buckets.contains(&buf.len()), "Packet of {} bytes isn't padded", buf.len());
+            assert!(buf.len() >= len + ENCRYPTED_HEADER_LENGTH + TAG_LENGTH);
+            assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], &msg[..]);
+        }
+
+        // Packets which don't fit into any bucket are left as is.
+        let msg = vec![1; 5000];
+        let mut buf = BytesMut::new();
+        initiator.encrypt_msg(&msg, &mut buf).unwrap();
+        assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], &msg[..]);
+    }
+
+    #[test]
+    fn test_padding_none() {
+        let (mut initiator, mut responder) = framed_pair(false, PaddingPolicy::None);
+        let lengths = padded_lengths(&mut initiator, &mut responder);
+        let overhead = NOISE_PACKET_HEADER_LENGTH + 1 + TAG_LENGTH;
+        assert_eq!(lengths, vec![0, 1, 10, 100, 200, 1000, 3000].iter().map(|len| len + overhead).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_padding_power_of_two() {
+        let (mut initiator, mut responder) = framed_pair(false, PaddingPolicy::PowerOfTwo);
+        let lengths = padded_lengths(&mut initiator, &mut responder);
+        assert_eq!(lengths, vec![32, 32, 32, 128, 256, 1024, 4096]);
+    }
+
+    #[test]
+    fn test_padding_fixed_bucket() {
+        let (mut initiator, mut responder) = framed_pair(false, PaddingPolicy::FixedBucket(512));
+        let lengths = padded_lengths(&mut initiator, &mut responder);
+        assert_eq!(lengths, vec![512, 512, 512, 512, 512, 1024, 3072]);
+    }
+
+    #[test]
+    fn test_padding_random() {
+        let (mut initiator, mut responder) = framed_pair(false, PaddingPolicy::Random(100));
+        let (mut plain_initiator, mut plain_responder) = framed_pair(false, PaddingPolicy::None);
+        let lengths = padded_lengths(&mut initiator, &mut responder);
+        let plain_lengths = padded_lengths(&mut plain_initiator, &mut plain_responder);
+
+        // Padded packet also carries 2 bytes of the padding length.
+        for (len, plain_len) in lengths.iter().zip(plain_lengths) {
+            assert!(*len >= plain_len + 2 && *len <= plain_len + 2 + 100);
+        }
+    }
+
+    #[test]
+    fn test_padding_large_msg() {
+        let (mut initiator, mut responder) = framed_pair(false, PaddingPolicy::PowerOfTwo);
+        let msg = vec![1; NOISE_MAX_PAYLOAD_LENGTH * 2];
+        let mut buf = BytesMut::new();
+        initiator.encrypt_msg(&msg, &mut buf).unwrap();
+
+        // Full packets can't grow beyond maximal Noise message length.
+        assert_eq!(buf.len(), 2 * (NOISE_PACKET_HEADER_LENGTH + NOISE_MAX_MESSAGE_LENGTH) + 32);
+        assert_eq!(&responder.decrypt_msg(&mut buf).unwrap().unwrap()[..], &msg[..]);
+    }
+}